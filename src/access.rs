@@ -0,0 +1,177 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// An address range like 192.168.1.0/24 or fe80::/10, a bare address is a range of one
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse::<u8>().ok()?)),
+            None => (text, None),
+        };
+        let network = address.parse::<IpAddr>().ok()?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return None;
+        }
+        Some(Self { network, prefix })
+    }
+
+    // IPv4 peers of a dual-stack listener show up as ::ffff:a.b.c.d
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => same_prefix(&network.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => same_prefix(&network.octets(), &ip.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn same_prefix(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let (whole_bytes, bits) = (prefix as usize / 8, prefix % 8);
+    if network[..whole_bytes] != ip[..whole_bytes] {
+        return false;
+    }
+    bits == 0 || (network[whole_bytes] ^ ip[whole_bytes]) >> (8 - bits) == 0
+}
+
+// Comma separated ranges, e.g. "10.0.0.0/8, 192.168.1.7"
+pub fn parse_rules(text: &str) -> Option<Vec<Cidr>> {
+    text.split(',')
+        .filter(|rule| !rule.trim().is_empty())
+        .map(Cidr::parse)
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Refusal {
+    NotAllowed,
+    Denied,
+    TooManyConnections,
+    Banned(Duration),
+}
+
+impl Refusal {
+    pub fn describe(self) -> String {
+        match self {
+            Refusal::NotAllowed => "it isn't in the allow list".to_string(),
+            Refusal::Denied => "it's in the deny list".to_string(),
+            Refusal::TooManyConnections => "it connects too often".to_string(),
+            Refusal::Banned(left) => format!("it's banned for another {}s", left.as_secs().max(1)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PeerRecord {
+    // connections within the last RATE_WINDOW
+    recent: VecDeque<Instant>,
+    failures: u32,
+    banned_until: Option<Instant>,
+}
+
+impl PeerRecord {
+    // Whether forgetting the peer would let it off something
+    fn holds_back(&self, now: Instant) -> bool {
+        self.failures > 0
+            || self.banned_until.is_some_and(|until| now < until)
+            || self.recent.back().is_some_and(|&connected| now.duration_since(connected) < RATE_WINDOW)
+    }
+}
+
+// Decides which peers a host lets through before anyone is asked about them.
+// Deny rules win over allow rules, an empty allow list allows everyone
+pub struct Gatekeeper {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    // 0 - no limit
+    connections_per_minute: u32,
+    // failed handshakes in a row before a ban, 0 - never banned
+    ban_after: u32,
+    ban: Duration,
+    peers: Mutex<HashMap<IpAddr, PeerRecord>>,
+    // peers that hold nothing back are forgotten once a window
+    pruned_at: Mutex<Instant>,
+}
+
+impl Gatekeeper {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>, connections_per_minute: u32, ban_after: u32, ban: Duration) -> Self {
+        Self { allow, deny, connections_per_minute, ban_after, ban, peers: Mutex::new(HashMap::new()), pruned_at: Mutex::new(Instant::now()) }
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Result<(), Refusal> {
+        self.check_at(ip, Instant::now())
+    }
+
+    // Every call counts as a connection, refused ones included
+    pub fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Refusal> {
+        if self.deny.iter().any(|rule| rule.contains(ip)) {
+            return Err(Refusal::Denied);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.contains(ip)) {
+            return Err(Refusal::NotAllowed);
+        }
+        let mut peers = self.peers.lock().unwrap();
+        let mut pruned_at = self.pruned_at.lock().unwrap();
+        if now.saturating_duration_since(*pruned_at) >= RATE_WINDOW {
+            peers.retain(|_, record| record.holds_back(now));
+            *pruned_at = now;
+        }
+        drop(pruned_at);
+        let record = peers.entry(ip.to_canonical()).or_default();
+        if let Some(until) = record.banned_until {
+            if now < until {
+                return Err(Refusal::Banned(until - now));
+            }
+            record.banned_until = None;
+            record.failures = 0;
+        }
+        while record.recent.front().is_some_and(|&connected| now.duration_since(connected) >= RATE_WINDOW) {
+            record.recent.pop_front();
+        }
+        record.recent.push_back(now);
+        if self.connections_per_minute > 0 && record.recent.len() > self.connections_per_minute as usize {
+            return Err(Refusal::TooManyConnections);
+        }
+        Ok(())
+    }
+
+    // How long the peer is banned for if this failure got it banned
+    pub fn record_failure(&self, ip: IpAddr) -> Option<Duration> {
+        self.record_failure_at(ip, Instant::now())
+    }
+
+    pub fn record_failure_at(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let mut peers = self.peers.lock().unwrap();
+        let record = peers.entry(ip.to_canonical()).or_default();
+        record.failures += 1;
+        if self.ban_after == 0 || record.failures < self.ban_after {
+            return None;
+        }
+        record.banned_until = Some(now + self.ban);
+        Some(self.ban)
+    }
+
+    // Only the tests look into the records
+    #[cfg(test)]
+    pub fn tracked_peers(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn record_success(&self, ip: IpAddr) {
+        if let Some(record) = self.peers.lock().unwrap().get_mut(&ip.to_canonical()) {
+            record.failures = 0;
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::io::{Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::time::{Instant, SystemTime};
+use tar::{Archive, Builder, EntryType, Header};
+use crate::packet::{ArchiveFormat, EntryKind};
+use crate::share::ShareSet;
+use crate::util;
+
+// Archives the offered entries, links are stored as links
+pub fn write_archive<W: Write>(writer: W, format: ArchiveFormat, share_set: &ShareSet) -> Result<W> {
+    match format {
+        ArchiveFormat::Tar => append_entries(Builder::new(writer), share_set),
+        ArchiveFormat::TarGz => {
+            let encoder = GzEncoder::new(writer, Compression::default());
+            append_entries(Builder::new(encoder), share_set)?.finish()
+        }
+    }
+}
+
+fn append_entries<W: Write>(mut builder: Builder<W>, share_set: &ShareSet) -> Result<W> {
+    for (file, source) in share_set.offer.files.iter().zip(&share_set.sources) {
+        match file.kind {
+            EntryKind::File => builder.append_path_with_name(source, &file.name)?,
+            EntryKind::Symlink | EntryKind::Hardlink => {
+                let mut header = Header::new_gnu();
+                let entry_type = if file.kind == EntryKind::Symlink { EntryType::Symlink } else { EntryType::Link };
+                header.set_entry_type(entry_type);
+                header.set_size(0);
+                header.set_mode(0o777);
+                let modified = source.symlink_metadata().and_then(|metadata| metadata.modified());
+                if let Ok(mtime) = modified.map(|time| time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default()) {
+                    header.set_mtime(mtime.as_secs());
+                }
+                builder.append_link(&mut header, &file.name, &file.link_target)?;
+            }
+        }
+    }
+    builder.into_inner()
+}
+
+pub fn extract_archive<R: Read>(reader: R, format: ArchiveFormat, destination: &Path) -> Result<()> {
+    match format {
+        ArchiveFormat::Tar => unpack_contained(Archive::new(reader), destination),
+        ArchiveFormat::TarGz => unpack_contained(Archive::new(GzDecoder::new(reader)), destination),
+    }
+}
+
+// Entries that would end up outside of destination are skipped by unpack_in, links are created
+// as they are though, so ones pointing outside of it are skipped like in a received directory
+fn unpack_contained<R: Read>(mut archive: Archive<R>, destination: &Path) -> Result<()> {
+    std::fs::create_dir_all(destination)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let name = entry.path()?.into_owned();
+            let target = entry.link_name()?.unwrap_or_default().into_owned();
+            // symlinks resolve against the directory they're in, hardlinks against the archive's root
+            let resolved_target = match entry_type.is_symlink() {
+                true => name.parent().unwrap_or(Path::new("")).join(&target),
+                false => target.clone(),
+            };
+            if !util::is_contained_path(resolved_target.to_str().unwrap_or("")) {
+                eprintln!("Skipping link {} -> {}, target is outside of the archive", name.display(), target.display());
+                continue;
+            }
+        }
+        entry.unpack_in(destination)?;
+    }
+    Ok(())
+}
+
+// Chunks written to the sink but not yet unpacked
+const EXTRACTION_BACKLOG: usize = 8;
+
+// Unpacks whatever is written to the returned sink on another thread, dropping the sink
+// ends the archive. The outcome is printed once the extraction is over
+pub fn extract_in_background(format: ArchiveFormat, destination: PathBuf) -> (ArchiveSink, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(EXTRACTION_BACKLOG);
+    let extraction = thread::spawn(move || {
+        let start = Instant::now();
+        let reader = ChunkReader { receiver, chunk: vec![], position: 0 };
+        match extract_archive(reader, format, &destination) {
+            Ok(_) => {
+                let time_format = util::format_time(start.elapsed().as_secs_f64());
+                println!("Extracted {} in {time_format}", destination.display());
+            }
+            Err(err) => eprintln!("Failed to extract archive: {err}"),
+        }
+    });
+    (ArchiveSink { sender }, extraction)
+}
+
+pub struct ArchiveSink {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl Write for ArchiveSink {
+    // the archive may end before the last chunk, anything after it is dropped
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        let _ = self.sender.send(data.to_vec());
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct ChunkReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.position == self.chunk.len() {
+            let Ok(chunk) = self.receiver.recv() else {
+                return Ok(0);
+            };
+            self.chunk = chunk;
+            self.position = 0;
+        }
+        let available = &self.chunk[self.position..];
+        let size = std::cmp::min(available.len(), buf.len());
+        buf[0..size].copy_from_slice(&available[0..size]);
+        self.position += size;
+        Ok(size)
+    }
+}
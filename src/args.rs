@@ -1,6 +1,13 @@
+use crate::util;
 
 pub const HOST: &str = "host";
 pub const CONNECT: &str = "connect";
+pub const SEND: &str = "send";
+pub const RECEIVE: &str = "receive";
+pub const SHARE: &str = "share";
+pub const DISCOVER: &str = "discover";
+pub const INTERFACES: &str = "interfaces";
+pub const RELAY: &str = "relay";
 
 // parse program specific arguments like flags
 // args: [program.exe, 0, 1, 2, ...]
@@ -8,9 +15,20 @@ pub struct ProgramArgs {
     // We can use 'exe' path for determining the relative location of config.txt
     pub exe: String,
     pub args: Vec<String>,
+    // arguments that aren't flags or flag values, in order
+    pub positional: Vec<String>,
     pub ip: Option<String>,
     pub port: Option<u16>,
     pub host_auto_accept: Option<bool>,
+    pub follow_symlinks: Option<bool>,
+    pub to_stdout: bool,
+    pub parallel_streams: Option<u8>,
+    pub rate_limit: Option<u64>,
+    pub unix_socket: Option<String>,
+    pub interface: Option<String>,
+    pub share_code: bool,
+    pub relay: Option<String>,
+    pub token: Option<String>,
 }
 
 impl ProgramArgs {
@@ -28,6 +46,16 @@ impl ProgramArgs {
         let mut port_arg = None;
         let mut ip_arg = None;
         let mut host_auto_accept = None;
+        let mut follow_symlinks = None;
+        let mut to_stdout = false;
+        let mut parallel_streams = None;
+        let mut rate_limit = None;
+        let mut unix_socket = None;
+        let mut interface = None;
+        let mut share_code = false;
+        let mut relay = None;
+        let mut token = None;
+        let mut positional = vec![];
         let mut i = 0;
         while i < length {
             let argument = &args[i];
@@ -50,10 +78,60 @@ impl ProgramArgs {
             }
             else if argument == "-aa" || argument == "--auto-accept" {
                 host_auto_accept = Some(true);
+            } else if argument == "-fs" || argument == "--follow-symlinks" {
+                follow_symlinks = Some(true);
+            } else if argument == "-ps" && i+1 < length {
+                match args[i+1].parse::<u8>() {
+                    Ok(streams) => parallel_streams = Some(streams),
+                    Err(_) => panic!("Failed to parse streams argument!"),
+                }
+                i += 1;
+            } else if let Some(streams) = argument.strip_prefix("--streams=") {
+                match streams.parse::<u8>() {
+                    Ok(streams) => parallel_streams = Some(streams),
+                    Err(_) => panic!("Failed to parse streams argument!"),
+                }
+            } else if argument == "--limit" && i+1 < length {
+                match util::parse_size(&args[i+1]) {
+                    Some(limit) => rate_limit = Some(limit),
+                    None => panic!("Failed to parse limit argument!"),
+                }
+                i += 1;
+            } else if let Some(limit) = argument.strip_prefix("--limit=") {
+                match util::parse_size(limit) {
+                    Some(limit) => rate_limit = Some(limit),
+                    None => panic!("Failed to parse limit argument!"),
+                }
+            } else if argument == "--unix" && i+1 < length {
+                unix_socket = Some(args[i+1].to_string());
+                i += 1;
+            } else if let Some(path) = argument.strip_prefix("--unix=") {
+                unix_socket = Some(path.to_string());
+            } else if argument == "--interface" && i+1 < length {
+                interface = Some(args[i+1].to_string());
+                i += 1;
+            } else if let Some(name) = argument.strip_prefix("--interface=") {
+                interface = Some(name.to_string());
+            } else if argument == "--relay" && i+1 < length {
+                relay = Some(args[i+1].to_string());
+                i += 1;
+            } else if let Some(address) = argument.strip_prefix("--relay=") {
+                relay = Some(address.to_string());
+            } else if argument == "--token" && i+1 < length {
+                token = Some(args[i+1].to_string());
+                i += 1;
+            } else if let Some(value) = argument.strip_prefix("--token=") {
+                token = Some(value.to_string());
+            } else if argument == "--code" {
+                share_code = true;
+            } else if argument == "--stdout" {
+                to_stdout = true;
+            } else if !argument.starts_with('-') || argument == "-" {
+                positional.push(argument.to_string());
             }
             i += 1;
         }
-        Self { exe: exe_path, args, ip: ip_arg, port: port_arg, host_auto_accept}
+        Self { exe: exe_path, args, positional, ip: ip_arg, port: port_arg, host_auto_accept, follow_symlinks, to_stdout, parallel_streams, rate_limit, unix_socket, interface, share_code, relay, token }
     }
 
     pub fn str_to_bool(flag: &str) -> bool {
@@ -70,9 +148,24 @@ impl ProgramArgs {
     pub fn print_info() {
         println!("fileserver {HOST} - listen for a connection");
         println!("fileserver {CONNECT} - initiate a connection");
+        println!("fileserver {SEND} [ip] <path> - send a single file, \"-\" sends stdin");
+        println!("fileserver {RECEIVE} [--stdout] - receive a single file, optionally into stdout");
+        println!("fileserver {SHARE} --code <path> - send a single file to whoever runs {RECEIVE} with the printed code");
+        println!("fileserver {RECEIVE} <code> [--stdout] - receive what was shared with a code");
+        println!("fileserver {DISCOVER} - list hosts on the local network, {CONNECT} without an ip picks from them");
+        println!("fileserver {INTERFACES} - list network interfaces a host can listen on");
+        println!("fileserver {RELAY} - pair clients connecting with the same token, for peers that can't reach each other");
+        println!("fileserver {CONNECT} --relay <host:port> --token <token> - connect to whoever uses the token at the relay");
         println!("Additional arguments:");
         println!("-ip, --ip=<string>");
         println!("-p, --port=<u16>");
+        println!("--interface=<name> - host on the interface's ip, \"all\" or \"all6\" listens on every interface");
         println!("-aa, --auto-accept=<bool>");
+        println!("-fs, --follow-symlinks");
+        println!("-ps, --streams=<u8> - extra connections for uploads");
+        println!("--limit=<size> - bandwidth cap per second, e.g. 20M");
+        println!("--unix=<path> - host on or connect to a unix socket instead of ip and port");
+        println!("--relay=<host:port>, --token=<string>");
+        println!("--stdout");
     }
 }
\ No newline at end of file
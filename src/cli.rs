@@ -1,61 +1,603 @@
-use std::collections::{HashMap};
+use std::collections::VecDeque;
 use std::fs::{File, OpenOptions};
-use std::io::{Write};
-use std::net::{Shutdown, TcpStream};
-use std::path::Path;
-use std::time::{Instant};
+use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::access::Gatekeeper;
 use crate::config::Config;
-use crate::{connection, packet, util};
-use crate::file_operator::FileFeeder;
-use crate::packet::{ BeginUploadPacket, DirectoryOfferPacket, FileOfferPacket, FilePacket, MB_1, Packet, PingPacket, SpeedPacket};
-use crate::speedtest::{round_trip_time, speedtest_in, speedtest_out};
-
-pub fn client_impl(config: Config) {
-    let target_address = config.connect_ip.as_ref().unwrap();
-    let port = config.connect_port.unwrap();
-    println!("Attempting connection to {target_address}");
-    let mut stream = match connection::connect_ipv4(target_address, port) {
-        Ok(tcp_stream) => tcp_stream,
+use crate::discovery::{Announcement, DISCOVERY_PORT, DISCOVERY_WAIT};
+use crate::identity::{Identity, KnownPeers, PublicKey, IDENTITY_NAME, KNOWN_PEERS_NAME};
+use crate::{archive, connection, discovery, identity, share_code, multiplex, packet, parallel, relay, selection, throttle, util};
+use crate::file_operator::{FileFeeder, HoleWriter, StreamFeeder};
+use crate::packet::{ArchiveFormat, ArchiveRequestPacket, BeginUploadPacket, Control, DirectoryOfferPacket, EntryKind, ErrorCode, ErrorPacket, FileOfferPacket, FilePacket, FilePacketWriter, GoodbyePacket, GoodbyeReason, HeartbeatPacket, HoleMapPacket, MB_1, Packet, ParallelStreamsPacket, PingPacket, RawPacket, RelayRole, SpeedPacket, SpeedtestInfoPacket};
+use crate::multiplex::{Downloads, Uploads};
+use crate::parallel::TransferFile;
+use crate::queue::{ShareJob, TransferQueue};
+use crate::proxy::Proxy;
+use crate::relay::RelayListener;
+use crate::session::{self, Exchange, Session};
+use crate::transport::{Listener, Transport};
+use crate::share::{ShareRequest, ShareSet};
+use crate::speedtest::{read_ping, round_trip_time, speedtest_in, speedtest_out, write_ping};
+
+pub fn client_impl(mut config: Config) {
+    if config.relay.is_some() {
+        relayed_client_impl(config);
+        return;
+    }
+    if config.connect_ip.is_none() && config.unix_socket.is_none() {
+        let Some(address) = pick_discovered_peer() else {
+            return;
+        };
+        config.connect_ip = Some(address.ip().to_string());
+        config.connect_port = Some(address.port());
+    }
+    let Some(identity) = load_identity() else {
+        return;
+    };
+    let Some(mut stream) = connect_to_peer(&config) else {
+        return;
+    };
+    let greeting = match session::greet(&mut stream, session::new_session_id(), &identity) {
+        Ok(greeting) => greeting,
+        Err(err) => {
+            eprintln!("Handshake failed: {err}");
+            return;
+        }
+    };
+    if trust_peer(&config, connection::peer_address(&config).as_deref(), &greeting.peer_key, &mut stream) != Trust::Trusted {
+        return;
+    }
+    let queue = TransferQueue::new();
+    let session = Session::new(greeting, stream, &config, &identity, None, None);
+    established_connection_stage(&session, &config, &queue);
+}
+
+// Both peers dial the relay, whoever waited there for the other one answers the greeting
+// and comes back to the relay whenever the session has to reconnect
+fn relayed_client_impl(config: Config) {
+    let (Some(relay_address), Some(token)) = (config.relay.clone(), config.relay_token.clone()) else {
+        eprintln!("Connecting through a relay needs a token, pass one with --token");
+        return;
+    };
+    let Some(identity) = load_identity() else {
+        return;
+    };
+    println!("Waiting for the peer at relay {relay_address}..");
+    let (mut stream, dialing) = match relay::join(&relay_address, &token, RelayRole::Any, config.proxy.as_deref()) {
+        Ok(joined) => joined,
+        Err(err) => {
+            eprintln!("Failed to join the relay: {err}");
+            return;
+        }
+    };
+    config.apply_timeouts(&stream);
+    let greeting = match dialing {
+        true => session::greet(&mut stream, session::new_session_id(), &identity),
+        false => session::answer_greeting(&mut stream, &identity),
+    };
+    let greeting = match greeting {
+        Ok(greeting) => greeting,
+        Err(err) => {
+            eprintln!("Handshake failed: {err}");
+            return;
+        }
+    };
+    println!("Connected through the relay, the peer's fingerprint is {}", identity::fingerprint(&greeting.peer_key));
+    // whoever turns up with the token is the same peer every time
+    if trust_peer(&config, Some(&format!("{relay_address}/{token}")), &greeting.peer_key, &mut stream) != Trust::Trusted {
+        return;
+    }
+    let listener = RelayListener { relay: relay_address, token, proxy: config.proxy.clone() };
+    let listener: Option<&dyn Listener> = match dialing {
+        true => None,
+        false => Some(&listener),
+    };
+    let queue = TransferQueue::new();
+    let session = Session::new(greeting, stream, &config, &identity, listener, None);
+    established_connection_stage(&session, &config, &queue);
+}
+
+fn load_identity() -> Option<Identity> {
+    match Identity::load_or_create(IDENTITY_NAME) {
+        Ok(identity) => Some(identity),
+        Err(err) => {
+            eprintln!("Failed to load the identity from {IDENTITY_NAME}: {err}");
+            None
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Trust {
+    Trusted,
+    // a first-seen key the user didn't trust
+    Declined,
+    // a known peer presenting another key, as good as a failed handshake
+    KeyChanged,
+}
+
+// Trust on first use: a peer seen for the first time is remembered along with its key once accepted,
+// from then on it has to present the same key. Dialing sides go by the address dialed, hosts by the peer's ip
+// and relayed peers by the relay and token, None if there's nothing to tell peers apart by.
+// Messages go to stderr since a received file may be written into stdout
+fn trust_peer(config: &Config, address: Option<&str>, peer_key: &PublicKey, stream: &mut dyn Transport) -> Trust {
+    let Some(address) = address else {
+        return Trust::Trusted;
+    };
+    let mut known_peers = KnownPeers::load(KNOWN_PEERS_NAME);
+    let fingerprint = identity::fingerprint(peer_key);
+    match known_peers.get(address) {
+        Some(known) if known == peer_key => return Trust::Trusted,
+        Some(known) => {
+            eprintln!("WARNING: {address} PRESENTED A DIFFERENT KEY THAN BEFORE, SOMEONE COULD BE IMPERSONATING IT");
+            eprintln!("Expected fingerprint {}, got {fingerprint}", identity::fingerprint(known));
+            eprintln!("Refusing to connect. If the peer's key did change, remove its line from {KNOWN_PEERS_NAME}");
+            write_goodbye(GoodbyeReason::Rejected, stream);
+            let _ = stream.shutdown(Shutdown::Both);
+            return Trust::KeyChanged;
+        }
+        None => eprintln!("First connection with {address}, its fingerprint is {fingerprint}"),
+    }
+    if !config.auto_accept.unwrap_or(false) {
+        eprintln!("Do you trust this key? (y/n)");
+        if !util::read_line().starts_with('y') {
+            write_goodbye(GoodbyeReason::Rejected, stream);
+            let _ = stream.shutdown(Shutdown::Both);
+            return Trust::Declined;
+        }
+    }
+    if let Err(err) = known_peers.remember(address, peer_key) {
+        eprintln!("Failed to remember the peer in {KNOWN_PEERS_NAME}: {err}");
+    }
+    Trust::Trusted
+}
+
+// The key hosts remember a peer by, unix socket peers are on this machine
+fn peer_ip_key(peer: &str) -> Option<String> {
+    peer.parse::<IpAddr>().ok().map(|ip| ip.to_canonical().to_string())
+}
+
+pub fn discover_impl() {
+    println!("Looking for hosts..");
+    match discovery::discover(DISCOVERY_PORT, DISCOVERY_WAIT) {
+        Ok(peers) if peers.is_empty() => println!("No hosts found"),
+        Ok(peers) => peers.iter().for_each(|peer| println!("{}", peer.describe())),
+        Err(err) => eprintln!("Discovery failed: {err}"),
+    }
+}
+
+fn pick_discovered_peer() -> Option<SocketAddr> {
+    println!("No address to connect to, looking for hosts..");
+    let mut peers = match discovery::discover(DISCOVERY_PORT, DISCOVERY_WAIT) {
+        Ok(peers) => peers,
+        Err(err) => {
+            eprintln!("Discovery failed: {err}");
+            return None;
+        }
+    };
+    if peers.is_empty() {
+        println!("No hosts found, pass one with -ip");
+        return None;
+    }
+    for (i, peer) in peers.iter().enumerate() {
+        println!("{}. {}", i + 1, peer.describe());
+    }
+    println!("Which host to connect to? (1-{})", peers.len());
+    let choice = util::read_line().trim().parse::<usize>().ok().filter(|&choice| 1 <= choice && choice <= peers.len());
+    let Some(choice) = choice else {
+        println!("No host was picked");
+        return None;
+    };
+    Some(peers.swap_remove(choice - 1).address)
+}
+
+fn connect_to_peer(config: &Config) -> Option<Box<dyn Transport>> {
+    // an address that doesn't parse is reported by connect
+    if let Some(target_address) = connection::peer_address(config) {
+        match config.proxy.as_deref().and_then(|proxy| Proxy::parse(proxy).ok()) {
+            Some(proxy) if config.unix_socket.is_none() => println!("Attempting connection to {target_address} through proxy {}", proxy.address),
+            _ => println!("Attempting connection to {target_address}"),
+        }
+    }
+    let stream = match connection::connect(config) {
+        Ok(stream) => stream,
         Err(err) => {
             let err_kind = err.kind();
             eprintln!("Error: \"{err_kind}\" - {err}");
-            return;
+            return None;
         }
     };
     println!("Connected!");
-    config.apply_timeouts(&mut stream);
-    established_connection_stage(stream);
+    config.apply_timeouts(&stream);
+    Some(stream)
 }
 
-pub fn server_impl(mut config: Config) {
-    println!("Setting up server");
+// Listens on the unix socket if one is set, otherwise on the host ip and port
+fn create_listener(config: &mut Config) -> Box<dyn Listener> {
+    if let Some(path) = &config.unix_socket {
+        return connection::create_unix_server(path);
+    }
     if config.host_ip.is_none() {
-        config.host_ip = Some(select_local_ip());
+        config.host_ip = Some(select_local_ip(config.host_interface.as_deref()));
     }
-
     let host_address = config.host_ip.as_ref().unwrap();
-    let port = config.host_port.unwrap();
-    let listener = connection::create_server(host_address, port);
-    let local_address = listener.local_addr().unwrap();
-    println!("Hosting server on {}:{}", local_address.ip(), local_address.port());
+    Box::new(connection::create_server(host_address, config.host_port))
+}
+
+// Offers a single path (or stdin if it's "-") and exits once it's uploaded
+pub fn send_impl(config: Config, shared_path: &str) {
+    let Some(identity) = load_identity() else {
+        return;
+    };
+    let Some(mut stream) = connect_to_peer(&config) else {
+        return;
+    };
+    let greeting = match session::greet(&mut stream, session::new_session_id(), &identity) {
+        Ok(greeting) => greeting,
+        Err(err) => {
+            eprintln!("Handshake failed: {err}");
+            return;
+        }
+    };
+    if trust_peer(&config, connection::peer_address(&config).as_deref(), &greeting.peer_key, &mut stream) != Trust::Trusted {
+        return;
+    }
+    offer_single_path(stream, shared_path, &config, true);
+}
+
+// Shares a single path with whoever has the printed code, nobody has to type an ip or a port
+pub fn share_code_impl(mut config: Config, shared_path: &str) {
+    let Some(identity) = load_identity() else {
+        return;
+    };
+    let code = share_code::generate();
+    let listener = create_listener(&mut config);
+    if let Some(port) = listener.tcp_port() {
+        let announcement = Announcement {
+            name: config.name.clone().unwrap_or_else(discovery::default_name),
+            port,
+            auto_accept: false,
+            parallel_streams: config.parallel_streams(),
+            nameplate: share_code::parse_nameplate(&code),
+        };
+        if let Err(err) = discovery::start_responder(announcement, DISCOVERY_PORT) {
+            eprintln!("Discovery is off ({err}), the receiver has to pass -ip and -p of {}", listener.describe());
+        }
+    }
+    println!("Share code: {code}");
+    println!("On the other side run: fileserver receive {code}");
+    let (mut stream, peer) = match accept_admitted(listener.as_ref(), &config.gatekeeper()) {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            eprintln!("Failed to accept connection: {err}");
+            return;
+        }
+    };
+    config.apply_timeouts(&stream);
+    let greeting = match session::answer_greeting(&mut stream, &identity) {
+        Ok(greeting) => greeting,
+        Err(err) => {
+            eprintln!("Handshake failed: {err}");
+            return;
+        }
+    };
+    // a code is good for a single attempt, guessing it again and again isn't an option
+    if let Err(err) = share_code::authenticate(&mut stream, &code, &greeting, &identity.public_key(), true) {
+        eprintln!("{peer} failed to confirm the code, the share is cancelled: {err}");
+        let _ = stream.shutdown(Shutdown::Both);
+        return;
+    }
+    println!("Code confirmed by {peer}");
+    if trust_peer(&config, peer_ip_key(&peer).as_deref(), &greeting.peer_key, &mut stream) != Trust::Trusted {
+        return;
+    }
+    offer_single_path(stream, shared_path, &config, false);
+}
+
+fn offer_single_path(stream: Box<dyn Transport>, shared_path: &str, config: &Config, dialing: bool) {
+    let transaction_id = session::transaction_id(1, dialing);
+    let control = Mutex::new(stream);
+    if shared_path == STDIN_PATH {
+        let _ = control.send(&FileOfferPacket::new_stream(transaction_id, STDIN_NAME.to_string()));
+        println!("Offered {STDIN_NAME} stream");
+        match control.await_reply(transaction_id) {
+            Ok(reply) if reply.id == ErrorPacket::ID => {
+                let _ = print_peer_error(STDIN_NAME, &reply);
+            }
+            reply => match reply.and_then(|reply| read_upload_packet(transaction_id, reply)) {
+                Ok(upload) if upload.has_any_files() => stream_reader(std::io::stdin().lock(), transaction_id, &control),
+                Ok(_) => println!("Stream denied!"),
+                Err(err) => eprintln!("{err}"),
+            },
+        }
+    } else if let Err(err) = share_file_or_directory(&ShareRequest::single(shared_path), transaction_id, &control, config) {
+        match read_parting_error(&control) {
+            Some(error) => eprintln!("Share couldn't complete, the receiver failed: {}", error.message),
+            None => eprintln!("Share couldn't complete: {err}"),
+        }
+    }
+    let _ = control.into_inner().unwrap().shutdown(Shutdown::Both);
+}
+
+// A receiver that fails tells why before closing the connection, the upload only sees a broken pipe
+fn read_parting_error(control: &dyn Control) -> Option<ErrorPacket> {
+    let mut stream = control.transport().ok()?;
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+    let packet = packet::poll_packet(&mut stream).ok()??;
+    if packet.id != ErrorPacket::ID {
+        return None;
+    }
+    ErrorPacket::from_bytes(&packet.content).ok()
+}
+
+// Accepts the first sender and its file offer without prompting, stdout is kept clean of messages
+pub fn receive_impl(mut config: Config, to_stdout: bool) {
+    let Some(identity) = load_identity() else {
+        return;
+    };
+    let listener = create_listener(&mut config);
+    eprintln!("Waiting for a sender on {}", listener.describe());
+    let (mut stream, peer) = match accept_admitted(listener.as_ref(), &config.gatekeeper()) {
+        Ok((stream, peer)) => {
+            eprintln!("Connected to {peer}!");
+            (stream, peer)
+        }
+        Err(err) => {
+            eprintln!("Failed to accept connection: {err}");
+            return;
+        }
+    };
+    config.apply_timeouts(&stream);
+    let greeting = match session::answer_greeting(&mut stream, &identity) {
+        Ok(greeting) => greeting,
+        Err(err) => {
+            eprintln!("Handshake failed: {err}");
+            return;
+        }
+    };
+    eprintln!("Sender's fingerprint is {}", identity::fingerprint(&greeting.peer_key));
+    if trust_peer(&config, peer_ip_key(&peer).as_deref(), &greeting.peer_key, &mut stream) != Trust::Trusted {
+        return;
+    }
+    receive_single_offer(stream, to_stdout);
+}
+
+const CODE_LOOKUPS: u32 = 10;
+
+// Finds the sharer by the code's nameplate unless it was given with -ip and -p,
+// messages go to stderr since the file may be written into stdout
+pub fn receive_code_impl(mut config: Config, code: &str, to_stdout: bool) {
+    let Some(nameplate) = share_code::parse_nameplate(code) else {
+        eprintln!("{code} isn't a share code, they look like 7-purple-sausage");
+        return;
+    };
+    let Some(identity) = load_identity() else {
+        return;
+    };
+    if config.connect_ip.is_none() && config.unix_socket.is_none() {
+        let Some(address) = find_sharer(nameplate) else {
+            return;
+        };
+        config.connect_ip = Some(address.ip().to_string());
+        config.connect_port = Some(address.port());
+    }
+    let mut stream = match connection::connect(&config) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("Failed to reach the sender: {err}");
+            return;
+        }
+    };
+    config.apply_timeouts(&stream);
+    let greeting = match session::greet(&mut stream, session::new_session_id(), &identity) {
+        Ok(greeting) => greeting,
+        Err(err) => {
+            eprintln!("Handshake failed: {err}");
+            return;
+        }
+    };
+    if let Err(err) = share_code::authenticate(&mut stream, code, &greeting, &identity.public_key(), false) {
+        eprintln!("Code exchange failed: {err}");
+        return;
+    }
+    eprintln!("Code confirmed");
+    receive_single_offer(stream, to_stdout);
+}
+
+fn find_sharer(nameplate: u16) -> Option<SocketAddr> {
+    for attempt in 1..=CODE_LOOKUPS {
+        let peers = match discovery::discover(DISCOVERY_PORT, DISCOVERY_WAIT) {
+            Ok(peers) => peers,
+            Err(err) => {
+                eprintln!("Discovery failed: {err}");
+                return None;
+            }
+        };
+        if let Some(peer) = peers.into_iter().find(|peer| peer.announcement.nameplate == Some(nameplate)) {
+            eprintln!("Found {}", peer.describe());
+            return Some(peer.address);
+        }
+        if attempt == 1 {
+            eprintln!("Waiting for the sender to show up..");
+        }
+    }
+    eprintln!("Nobody shares with code {nameplate}-.. on the local network, the sender can be given with -ip and -p");
+    None
+}
+
+fn receive_single_offer(mut stream: Box<dyn Transport>, to_stdout: bool) {
+    let offer = packet::read_id(&mut stream).and_then(|id| {
+        let packet_size = packet::read_content_size(&mut stream)?;
+        Ok((id, packet::read_into_new_buffer(&mut stream, packet_size)?))
+    });
+    let (id, field_buffer) = match offer {
+        Ok(offer) => offer,
+        Err(err) => {
+            eprintln!("Sender left before offering anything: {err}");
+            return;
+        }
+    };
+    if id == GoodbyePacket::ID {
+        eprintln!("Sender left before offering anything");
+        return;
+    }
+    if id != FileOfferPacket::ID {
+        eprintln!("Only file offers can be received in this mode, got {id}");
+        let message = "Only single files and streams can be received in this mode".to_string();
+        write_error_packet(ErrorPacket::new(0, ErrorCode::Unsupported, message), &mut stream);
+        return;
+    }
+    let file_offer = match FileOfferPacket::construct(&field_buffer) {
+        Ok(offer) => offer,
+        Err(err) => {
+            eprintln!("Failure: {err}");
+            return;
+        }
+    };
+    if !to_stdout && !util::is_contained_path(&file_offer.file_name) {
+        eprintln!("Denied offer, invalid name {}", file_offer.file_name);
+        let message = format!("Invalid name {}", file_offer.file_name);
+        write_error_packet(ErrorPacket::new(file_offer.transaction_id, ErrorCode::InvalidName, message), &mut stream);
+        return;
+    }
+
+    let current_size = if to_stdout || file_offer.is_stream() {
+        0
+    } else {
+        Path::new(&file_offer.file_name).metadata().map(|m| m.len()).unwrap_or(0)
+    };
+    if !file_offer.is_stream() && current_size >= file_offer.file_size && current_size > 0 {
+        eprintln!("Denied offer because current size >= offered");
+        write_denied_packet(file_offer.transaction_id, &mut stream);
+        return;
+    }
+    let accept_upload = BeginUploadPacket::single_file(file_offer.transaction_id, current_size);
+    let _ = accept_upload.write_header(&mut stream);
+    let _ = accept_upload.write(&mut stream);
+
+    // stdout can only be written in order
+    let mut lanes = vec![];
+    if !file_offer.is_stream() {
+        lanes = match parallel::join_lanes(&mut stream, !to_stdout) {
+            Ok(lanes) => lanes,
+            Err(err) => {
+                eprintln!("Failed to set up the download: {err}");
+                return;
+            }
+        };
+    }
+    let transaction_id = file_offer.transaction_id;
+    let result = if !lanes.is_empty() {
+        let path = PathBuf::from(&file_offer.file_name);
+        let file = TransferFile { path, cursor: current_size, size: file_offer.file_size };
+        parallel::download(&mut lanes, &[file], transaction_id)
+    } else if to_stdout {
+        if file_offer.is_stream() {
+            read_stream_to_writer(std::io::stdout().lock(), transaction_id, &mut stream)
+        } else {
+            read_and_write_file_to_disk(0, file_offer.file_size, std::io::stdout(), transaction_id, &mut stream)
+        }
+    } else {
+        let file = if file_offer.is_stream() {
+            File::create(&file_offer.file_name)
+        } else {
+            OpenOptions::new().create(true).append(true).open(&file_offer.file_name)
+        };
+        let file = match file {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Failed to open {}: {err}", file_offer.file_name);
+                write_error_packet(ErrorPacket::from_error(transaction_id, &err), &mut stream);
+                return;
+            }
+        };
+        if file_offer.is_stream() {
+            read_stream_to_writer(BufWriter::new(file), transaction_id, &mut stream)
+        } else {
+            read_and_write_file_to_disk(current_size, file_offer.file_size, file, transaction_id, &mut stream)
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("Download couldn't complete: {err}");
+        write_error_packet(ErrorPacket::from_error(transaction_id, &err), &mut stream);
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// Relays don't take part in sessions, they only need an address both peers can reach
+pub fn relay_impl(mut config: Config) {
+    println!("Setting up relay");
+    let listener = create_listener(&mut config);
+    println!("Relaying on {}", listener.describe());
+    relay::serve(listener.as_ref());
+}
+
+pub fn server_impl(mut config: Config) {
+    println!("Setting up server");
+    let Some(identity) = load_identity() else {
+        return;
+    };
+    let listener = create_listener(&mut config);
+    println!("Hosting server on {}", listener.describe());
+    if let Some(port) = listener.tcp_port().filter(|_| config.is_discoverable()) {
+        let announcement = Announcement {
+            name: config.name.clone().unwrap_or_else(discovery::default_name),
+            port,
+            auto_accept: config.auto_accept.unwrap_or(false),
+            parallel_streams: config.parallel_streams(),
+            nameplate: None,
+        };
+        if let Err(err) = discovery::start_responder(announcement, DISCOVERY_PORT) {
+            eprintln!("Discovery is off, couldn't listen for probes: {err}");
+        }
+    }
 
     let auto_accept = if let Some(accept) = config.auto_accept { accept } else { false };
+    let gatekeeper = config.gatekeeper();
+    let queue = TransferQueue::new();
     // Connection listener implementation
-    for incoming_conn in listener.incoming() {
-        match incoming_conn {
-            Ok(mut stream) => {
-                let ip = stream.peer_addr().unwrap().ip();
+    loop {
+        match accept_admitted(listener.as_ref(), &gatekeeper) {
+            Ok((mut stream, peer)) => {
+                let peer_ip = peer.parse::<IpAddr>().ok();
                 if !auto_accept {
-                    println!("Do you want to accept connection from: {} (y/n)", ip);
+                    println!("Do you want to accept connection from: {} (y/n)", peer);
                     if !util::read_line().starts_with('y') {
+                        reject_connection(&mut stream);
+                        continue;
+                    }
+                }
+                println!("Connected to {peer}!");
+                config.apply_timeouts(&stream);
+                let greeting = match session::answer_greeting(&mut stream, &identity) {
+                    Ok(greeting) => greeting,
+                    Err(err) => {
+                        eprintln!("Handshake failed: {err}");
                         let _ = stream.shutdown(Shutdown::Both);
+                        record_failure(&gatekeeper, &peer, peer_ip);
+                        continue;
+                    }
+                };
+                println!("Peer's fingerprint is {}", identity::fingerprint(&greeting.peer_key));
+                match trust_peer(&config, peer_ip_key(&peer).as_deref(), &greeting.peer_key, &mut stream) {
+                    Trust::Trusted => {}
+                    trust => {
+                        if trust == Trust::KeyChanged {
+                            record_failure(&gatekeeper, &peer, peer_ip);
+                        }
+                        println!("Listening for new connections..");
                         continue;
                     }
                 }
-                let peer_addr = stream.peer_addr().unwrap().ip();
-                println!("Connected to {peer_addr}!");
-                config.apply_timeouts(&mut stream);
-                established_connection_stage(stream);
+                if let Some(ip) = peer_ip {
+                    gatekeeper.record_success(ip);
+                }
+                let session = Session::new(greeting, stream, &config, &identity, Some(listener.as_ref()), Some(&gatekeeper));
+                established_connection_stage(&session, &config, &queue);
                 println!("Closed socket, listening for new connections..");
             }
             Err(err) => {
@@ -66,361 +608,1094 @@ pub fn server_impl(mut config: Config) {
     };
 }
 
-// eth or enp - ETHERNET
-// wlan or wlp - WIFI
-// lo - local
-pub fn select_local_ip() -> String {
-    match local_ip_address::local_ip() {
-        Ok(ip) => {
-            println!("LOCAL IP: {:?}", ip);
-            return ip.to_string();
+fn record_failure(gatekeeper: &Gatekeeper, peer: &str, peer_ip: Option<IpAddr>) {
+    if let Some(ban) = peer_ip.and_then(|ip| gatekeeper.record_failure(ip)) {
+        println!("Banned {peer} for {}s after repeated failed handshakes", ban.as_secs());
+    }
+}
+
+// Peers the gatekeeper refuses are turned away and the next one is waited for.
+// Messages go to stderr since a received file may be written into stdout
+fn accept_admitted(listener: &dyn Listener, gatekeeper: &Gatekeeper) -> std::io::Result<(Box<dyn Transport>, String)> {
+    loop {
+        let (mut stream, peer) = listener.accept_transport()?;
+        // unix socket peers are on this machine, the rules only cover addresses
+        let Some(Err(refusal)) = peer.parse::<IpAddr>().ok().map(|ip| gatekeeper.check(ip)) else {
+            return Ok((stream, peer));
+        };
+        eprintln!("Refused connection from {peer}, {}", refusal.describe());
+        reject_connection(&mut stream);
+    }
+}
+
+// Binds every interface instead of a single one
+const ALL_INTERFACES: &str = "all";
+const ALL_INTERFACES_V6: &str = "all6";
+
+// eth or enp - ETHERNET
+// wlan or wlp - WIFI
+// lo - local
+pub fn select_local_ip(interface: Option<&str>) -> String {
+    match interface {
+        Some(ALL_INTERFACES) => return "0.0.0.0".to_string(),
+        Some(ALL_INTERFACES_V6) => return "::".to_string(),
+        Some(name) => return interface_ip(name),
+        None => {}
+    }
+    match local_ip_address::local_ip() {
+        Ok(ip) => {
+            eprintln!("LOCAL IP: {:?}", ip);
+            return ip.to_string();
+        }
+        Err(err) => panic!("Couldn't assign default ip: {err}")
+    }
+}
+
+// IPv4 is preferred, link-local IPv6 addresses can't be bound without a scope
+fn interface_ip(name: &str) -> String {
+    let interfaces = local_ip_address::list_afinet_netifas().unwrap_or_default();
+    let addresses: Vec<IpAddr> = interfaces.into_iter()
+        .filter(|(interface, _)| interface == name)
+        .map(|(_, ip)| ip)
+        .collect();
+    let ip = addresses.iter().find(|ip| ip.is_ipv4())
+        .or_else(|| addresses.iter().find(|ip| !matches!(ip, IpAddr::V6(v6) if v6.is_unicast_link_local())));
+    match ip {
+        Some(ip) => {
+            eprintln!("LOCAL IP: {ip} ({name})");
+            ip.to_string()
+        }
+        None => panic!("Interface {name} has no usable address, run `fileserver interfaces` to list them"),
+    }
+}
+
+pub fn interface_kind(name: &str) -> &'static str {
+    const KINDS: [(&str, &str); 12] = [
+        ("eth", "ethernet"), ("enp", "ethernet"), ("eno", "ethernet"), ("ens", "ethernet"),
+        ("wlan", "wifi"), ("wlp", "wifi"), ("lo", "local"),
+        ("docker", "virtual"), ("br-", "virtual"), ("veth", "virtual"), ("virbr", "virtual"), ("tun", "vpn"),
+    ];
+    KINDS.iter()
+        .find(|(prefix, _)| name.starts_with(prefix))
+        .map_or("", |(_, kind)| kind)
+}
+
+// Candidates for --interface, the one picked by default is marked
+pub fn interfaces_impl() {
+    let interfaces = match local_ip_address::list_afinet_netifas() {
+        Ok(interfaces) => interfaces,
+        Err(err) => {
+            eprintln!("Failed to list network interfaces: {err}");
+            return;
+        }
+    };
+    let default_ip = local_ip_address::local_ip().ok();
+    for (name, ip) in interfaces {
+        let kind = interface_kind(&name);
+        let default = if Some(ip) == default_ip { " *default" } else { "" };
+        match kind {
+            "" => println!("{name} {ip}{default}"),
+            _ => println!("{name} {ip} ({kind}){default}"),
+        }
+    }
+    println!("{ALL_INTERFACES} 0.0.0.0, {ALL_INTERFACES_V6} :: - every interface at once");
+}
+
+const PINGS: usize = 100;
+// How often threads waiting on the user or the peer check whether the session was closed
+const SESSION_POLL: Duration = Duration::from_millis(200);
+// Sent whatever our own idle_timeout is, the peer's may be as short as 15s
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const STDIN_PATH: &str = "-";
+const STDIN_NAME: &str = "stdin";
+
+fn established_connection_stage(session: &Session, config: &Config, queue: &TransferQueue) {
+    queue.resume();
+    let inbox = Inbox::new();
+    thread::scope(|scope| {
+        let inbox = &inbox;
+        scope.spawn(|| drain_queue(queue, session, config));
+        scope.spawn(move || route_session(scope, session, inbox));
+        scope.spawn(move || send_heartbeats(session));
+        if let Some(idle_timeout) = config.idle_timeout() {
+            scope.spawn(move || watch_peer(session, idle_timeout));
+        }
+        loop {
+            println!("[share <paths>, queue [rm N, move N top|bottom], limit [20M|off], read, rtt 1, rtt 2, speedtest in, speedtest out, shutdown, test_send]");
+            let Some(line) = read_command(session) else {
+                queue.pause();
+                return;
+            };
+            let command = line.as_str();
+            println!("[{command}]");
+            if command.starts_with("shutdown") {
+                session.goodbye(GoodbyeReason::Shutdown);
+                queue.pause();
+                return;
+            } else if command.starts_with("share") {
+                let Some(whitespace) = command.find(' ') else {
+                    continue
+                };
+                let arguments = util::split_arguments(&command[whitespace + 1..]);
+                let id = queue.push(ShareRequest::parse(&arguments));
+                println!("Queued share #{id}");
+                continue;
+            } else if command.starts_with("queue") {
+                manage_queue(queue, command);
+                continue;
+            } else if command.starts_with("limit") {
+                // applies to transfers in progress too
+                throttle::handle_limit_command(command);
+                continue;
+            } else if command.starts_with("read") {
+                answer_next_offer(session, inbox);
+                continue;
+            } else if command.starts_with("test_send") {
+                let _ = session.send(&PingPacket::new_ping());
+                continue;
+            }
+
+            // the peer runs the matching command, transfers carry on meanwhile
+            let exchange = session.exchange();
+            if command.starts_with("speedtest in") || command.starts_with("si") {
+                speedtest_in(&exchange);
+            } else if command.starts_with("speedtest out") || command.starts_with("so") {
+                speedtest_out(&exchange);
+            } else if command.starts_with("rtt 1") {
+                print_round_trips(&exchange);
+                let _ = write_ping(&exchange);
+            } else if command.starts_with("rtt 2") && read_ping(&exchange).is_ok() {
+                print_round_trips(&exchange);
+            }
+        }
+    });
+}
+
+// None once the session is closed, e.g. when the peer stopped responding
+fn read_command(session: &Session) -> Option<String> {
+    while !session.is_closed() {
+        if let Some(line) = util::read_line_timeout(SESSION_POLL) {
+            return Some(line);
+        }
+    }
+    None
+}
+
+// Keeps the peer's watchdog from closing an idle session
+fn send_heartbeats(session: &Session) {
+    let mut last_sent = Instant::now();
+    while !session.is_closed() {
+        thread::sleep(SESSION_POLL);
+        // the old socket is dead while reconnecting, writing to it only reports broken pipes
+        if last_sent.elapsed() >= HEARTBEAT_INTERVAL && !session.is_reconnecting() {
+            let _ = session.send(&HeartbeatPacket::new());
+            last_sent = Instant::now();
+        }
+    }
+}
+
+// A peer that vanished without closing the connection (e.g. a closed laptop) never breaks a read,
+// so the session is closed once nothing arrived for idle_timeout, heartbeats included
+pub fn watch_peer(session: &Session, idle_timeout: Duration) {
+    while !session.is_closed() {
+        thread::sleep(SESSION_POLL);
+        if session.is_reconnecting() || session.idle_for() < idle_timeout {
+            continue;
+        }
+        eprintln!("Peer is unresponsive, nothing arrived for {}s. Closing the session", idle_timeout.as_secs());
+        // in case it still listens
+        session.goodbye(GoodbyeReason::Unresponsive);
+    }
+}
+
+fn print_round_trips(exchange: &Exchange) {
+    for p in 0..PINGS {
+        match round_trip_time(exchange) {
+            Ok(rtt) => println!("{p}# RTT: {:?}", rtt),
+            Err(err) => {
+                eprintln!("Round trip failed: {err}");
+                return;
+            }
+        }
+    }
+}
+
+// queue, queue rm N, queue move N top, queue move N bottom
+fn manage_queue(queue: &TransferQueue, command: &str) {
+    let arguments: Vec<&str> = command.split_whitespace().collect();
+    match arguments.as_slice() {
+        ["queue"] => queue.print(),
+        ["queue", "rm", position] => match position.parse::<usize>().ok().and_then(|p| queue.remove(p)) {
+            Some(job) => println!("Removed share #{}", job.id),
+            None => eprintln!("There's no queued share at {position}"),
+        },
+        ["queue", "move", position, place @ ("top" | "bottom")] => {
+            let Ok(position) = position.parse::<usize>() else {
+                eprintln!("Invalid position <{position}>");
+                return;
+            };
+            let moved = if *place == "top" { queue.move_to_top(position) } else { queue.move_to_bottom(position) };
+            if moved {
+                queue.print();
+            } else {
+                eprintln!("There's no queued share at {position}");
+            }
+        }
+        _ => eprintln!("Usage: queue, queue rm N, queue move N top|bottom"),
+    }
+}
+
+// Uploads queued shares, up to multiplex::MAX_ACTIVE of them at a time over the connection.
+// Shares cut off by a drop are offered again under the same transactions once reconnected
+fn drain_queue(queue: &TransferQueue, session: &Session, config: &Config) {
+    while let Some(job) = queue.next_job() {
+        let generation = session.generation();
+        let mut active = vec![];
+        let result = upload_jobs(job, &mut active, queue, session, config);
+        let Err(err) = result else {
+            continue;
+        };
+        let ids: Vec<String> = active.iter().map(|job| format!("#{}", job.id)).collect();
+        let ids = ids.join(", ");
+        // requeued from the back so that they keep their order
+        for job in active.into_iter().rev() {
+            queue.requeue(job);
+        }
+        eprintln!("Interrupted share {ids} ({err})");
+        if !session.reconnect(generation) {
+            eprintln!("Interrupted share {ids} will be offered again after connecting");
+            queue.pause();
+            return;
+        }
+    }
+}
+
+// Takes further jobs off the queue while fewer than MAX_ACTIVE are uploading, active holds
+// the jobs that aren't complete yet. One offer at a time waits for its answer, the accepted
+// uploads keep going in the meantime
+fn upload_jobs(first: ShareJob, active: &mut Vec<ShareJob>, queue: &TransferQueue, session: &Session, config: &Config) -> std::io::Result<()> {
+    let mut uploads = Uploads::new();
+    let mut next = Some(first);
+    let mut pending: Option<(u64, OfferedShare)> = None;
+    loop {
+        if pending.is_none() {
+            if let Some(job) = next.take() {
+                let transaction_id = session.transaction_id(job.id);
+                active.push(job);
+                match offer_share(&active[active.len() - 1].request, transaction_id, session, config)? {
+                    Some(offered) => pending = Some((transaction_id, offered)),
+                    None => finish_job(transaction_id, active, queue, session),
+                }
+            }
+        }
+        if let Some(transaction_id) = pending.as_ref().map(|(id, _)| *id) {
+            let reply = if uploads.is_empty() {
+                Some(session.await_reply(transaction_id)?)
+            } else {
+                session.try_reply(transaction_id)
+            };
+            if let Some(reply) = reply {
+                let (_, offered) = pending.take().unwrap();
+                match accept_share(offered, reply, transaction_id, session, config)? {
+                    Some((name, files)) => uploads.add(transaction_id, name, files),
+                    None => finish_job(transaction_id, active, queue, session),
+                }
+            }
+        }
+        if uploads.is_empty() && pending.is_none() && next.is_none() {
+            return Ok(());
+        }
+        if let Some(finished) = uploads.send_next(session)? {
+            finish_job(finished, active, queue, session);
+        }
+        // the peer may give up on any of the uploads, e.g. once its disk is full
+        for transaction_id in uploads.transaction_ids() {
+            let Some(reply) = session.try_reply(transaction_id) else {
+                continue;
+            };
+            let name = uploads.cancel(transaction_id).unwrap();
+            print_peer_error(&name, &reply)?;
+            finish_job(transaction_id, active, queue, session);
+        }
+        if next.is_none() && uploads.len() + (pending.is_some() as usize) < multiplex::MAX_ACTIVE {
+            next = queue.try_next_job();
+        }
+    }
+}
+
+fn finish_job(transaction_id: u64, active: &mut Vec<ShareJob>, queue: &TransferQueue, session: &Session) {
+    let position = active.iter().position(|job| session.transaction_id(job.id) == transaction_id).unwrap();
+    queue.finish_job(active.remove(position).id);
+}
+
+// Errors mean the connection can't be used anymore, a denied offer isn't an error
+pub fn share_file_or_directory(request: &ShareRequest, transaction_id: u64, control: &dyn Control, config: &Config) -> std::io::Result<()> {
+    let Some(offered) = offer_share(request, transaction_id, control, config)? else {
+        return Ok(());
+    };
+    let reply = control.await_reply(transaction_id)?;
+    let Some((name, files)) = accept_share(offered, reply, transaction_id, control, config)? else {
+        return Ok(());
+    };
+    let mut uploads = Uploads::new();
+    uploads.add(transaction_id, name, files);
+    uploads.send_all(control)
+}
+
+// A share that was offered and waits for the peer's answer
+enum OfferedShare {
+    File { path: String, name: String },
+    Directory(ShareSet),
+}
+
+impl OfferedShare {
+    fn name(&self) -> &str {
+        match self {
+            OfferedShare::File { name, .. } => name,
+            OfferedShare::Directory(share_set) => &share_set.offer.directory_name,
         }
-        Err(err) => panic!("Couldn't assign default ip: {err}")
     }
 }
 
-const PINGS: usize = 100;
+// None if there's nothing to offer
+fn offer_share(request: &ShareRequest, transaction_id: u64, control: &dyn Control, config: &Config) -> std::io::Result<Option<OfferedShare>> {
+    if let Some(shared_path) = request.single_file() {
+        let file_name = util::get_path_name(shared_path);
+        offer_file(shared_path, file_name, transaction_id, control)?;
+        return Ok(Some(OfferedShare::File { path: shared_path.to_string(), name: file_name.to_string() }));
+    }
 
-fn established_connection_stage(mut stream: TcpStream) {
-    loop {
-        println!("[share <path>, read, rtt 1, rtt 2, speedtest in, speedtest out, shutdown, test_send]");
-        let line = util::read_line();
-        let command = line.as_str();
-        println!("[{command}]");
-        if command.starts_with("shutdown") {
-            let _ = stream.shutdown(Shutdown::Both);
-            return;
-        } else if command.starts_with("share") {
-            let Some(whitespace) = command.find(' ') else {
-                continue
+    let mut share_set = ShareSet::collect(request, config.follows_symlinks());
+    share_set.offer.transaction_id = transaction_id;
+    if share_set.offer.file_count == 0 {
+        println!("No files found");
+        return Ok(None);
+    }
+    control.send(&share_set.offer)?;
+    println!("Offered {} files.", share_set.offer.file_count);
+    Ok(Some(OfferedShare::Directory(share_set)))
+}
+
+// Takes the peer's answer and returns the files to upload once the share is accepted. Denied
+// offers, archives and uploads over parallel streams are done by the time it returns
+fn accept_share(offered: OfferedShare, reply: RawPacket, transaction_id: u64, control: &dyn Control, config: &Config) -> std::io::Result<Option<(String, Vec<TransferFile>)>> {
+    if reply.id == ErrorPacket::ID {
+        print_peer_error(offered.name(), &reply)?;
+        return Ok(None);
+    }
+    let share_set = match offered {
+        OfferedShare::File { path, name } => {
+            let upload = read_upload_packet(transaction_id, reply)?;
+            if !upload.has_any_files() {
+                println!("File denied!");
+                return Ok(None);
+            }
+            println!("File was accepted.");
+            let size = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(err) => {
+                    eprintln!("Can't upload {name}: {err}");
+                    report_error(transaction_id, &err, control);
+                    return Ok(None);
+                }
             };
-            let file_path = command[whitespace + 1..].trim_matches('\"');
-            share_file_or_directory(file_path, &mut stream);
-
-        } else if command.starts_with("read") {
-            read_and_handle_packet(&mut stream);
-        } else if command.starts_with("speedtest in") || command.starts_with("si") {
-            speedtest_in(&mut stream);
-        } else if command.starts_with("speedtest out") || command.starts_with("so") {
-            speedtest_out(&mut stream);
-        } else if command.starts_with("rtt 1") {
-            for p in 0..PINGS {
-                let rtt = round_trip_time(&mut stream);
-                println!("{p}# RTT: {:?}", rtt);
-            }
-            write_ping(&mut stream);
-        } else if command.starts_with("rtt 2") {
-            read_ping(&mut stream);
-            for p in 0..PINGS {
-                let rtt = round_trip_time(&mut stream);
-                println!("{p}# RTT: {:?}", rtt);
-            }
-        } else if command.starts_with("test_send") {
-            write_ping(&mut stream);
-        }
-    }
-}
-
-pub fn share_file_or_directory(shared_path: &str, stream: &mut TcpStream) {
-    let path = Path::new(shared_path);
-    if !path.exists() {
-        eprintln!("File or directory not found!");
-        return
-    }
-    if path.is_dir() {
-        let dir_offer = DirectoryOfferPacket::new(shared_path);
-        if dir_offer.file_count == 0 {
-            println!("No files found");
-            return;
+            let files = vec![TransferFile { path: PathBuf::from(path), cursor: upload.cursors[0], size }];
+            return upload_over_lanes(name, files, transaction_id, control, config);
         }
-        let _ = dir_offer.write_header(stream);
-        let _ = dir_offer.write(stream);
-        println!("Offered {} files.", dir_offer.file_count);
+        OfferedShare::Directory(share_set) => share_set,
+    };
 
-        let id = packet::read_id(stream);
-        if id != BeginUploadPacket::ID {
-            eprintln!("Unexpected packet ID={id}");
-            return;
+    if reply.id == ArchiveRequestPacket::ID {
+        let request = ArchiveRequestPacket::from_bytes(&reply.content).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        if request.transaction_id != transaction_id {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Archive was requested for transaction {}", request.transaction_id)));
         }
-        let packet_size = packet::read_content_size(stream);
-        let buffer: Vec<u8> = packet::read_into_new_buffer(stream, packet_size);
-        let upload = BeginUploadPacket::from_bytes(&buffer);
+        stream_archive(&share_set, request, control)?;
+        return Ok(None);
+    }
+    let upload = read_upload_packet(transaction_id, reply)?;
 
-        if !upload.has_any_files() {
-            println!("Directory upload was cancelled!");
-            return;
-        }
-        println!("Directory was accepted.");
+    if !upload.has_any_files() {
+        println!("Directory upload was cancelled!");
+        return Ok(None);
+    }
+    println!("Directory was accepted.");
 
-        for (i, index) in upload.file_indexes.iter().enumerate() {
-            let file_shared = &dir_offer.files[*index as usize];
-            let cursor = upload.cursors[i];
-            let relative_path = path.join(&file_shared.name);
-            let path_str = relative_path.to_str().unwrap();
-            stream_file(path_str, cursor, stream);
+    let dir_offer = &share_set.offer;
+    let mut files = Vec::with_capacity(upload.file_indexes.len());
+    for (i, index) in upload.file_indexes.iter().enumerate() {
+        let Some(file_shared) = dir_offer.files.get(*index as usize) else {
+            let message = format!("Peer requested file {index} which wasn't offered");
+            return Err(Error::new(ErrorKind::InvalidData, message));
+        };
+        if !file_shared.is_file() {
+            let message = format!("Peer requested {} which is a link", file_shared.name);
+            return Err(Error::new(ErrorKind::InvalidData, message));
         }
+        let path = share_set.sources[*index as usize].clone();
+        files.push(TransferFile { path, cursor: upload.cursors[i], size: file_shared.size });
+    }
+    upload_over_lanes(dir_offer.directory_name.clone(), files, transaction_id, control, config)
+}
 
-        return;
+// Files go over parallel streams if the peer joins them, otherwise they're returned
+// to be sent over the connection next to other uploads
+fn upload_over_lanes(name: String, files: Vec<TransferFile>, transaction_id: u64, control: &dyn Control, config: &Config) -> std::io::Result<Option<(String, Vec<TransferFile>)>> {
+    let mut lanes = parallel::open_lanes(control, config.parallel_streams(), transaction_id)?;
+    if lanes.is_empty() {
+        return Ok(Some((name, files)));
     }
+    parallel::upload(&mut lanes, &files, transaction_id)?;
+    Ok(None)
+}
 
-    let file_name = util::get_path_name(shared_path);
-    let upload = offer_file(shared_path, file_name, stream);
-    if upload.has_any_files() {
-        println!("File was accepted.");
-        stream_file(shared_path, upload.cursors[0], stream);
-    } else {
-        println!("File denied!");
+fn stream_archive(share_set: &ShareSet, request: ArchiveRequestPacket, control: &dyn Control) -> std::io::Result<()> {
+    println!("Directory was requested as {} archive.", request.format.extension());
+    let start = Instant::now();
+    let writer = FilePacketWriter::new(control, request.transaction_id);
+    archive::write_archive(writer, request.format, share_set)?.finish()?;
+    let time_format = util::format_time(start.elapsed().as_secs_f64());
+    println!("Archive upload completed in {time_format}");
+    Ok(())
+}
+
+fn offer_file(file_path: &str, file_name: &str, transaction_id: u64, control: &dyn Control) -> std::io::Result<()> {
+    let metadata = std::fs::metadata(file_path)?;
+    control.send(&FileOfferPacket::new(transaction_id, metadata.len(), file_name.to_string()))?;
+    println!("Offered {file_name} file");
+    Ok(())
+}
+
+// The peer gave up on one of our offers or uploads
+fn print_peer_error(name: &str, reply: &RawPacket) -> std::io::Result<()> {
+    if reply.id != ErrorPacket::ID {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} wasn't expected during the upload of {name}", reply.id)));
     }
+    let error = ErrorPacket::from_bytes(&reply.content).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    eprintln!("{name} failed on the peer's side: {}", error.message);
+    Ok(())
 }
 
-fn offer_file(file_path: &str, file_name: &str, stream: &mut TcpStream) -> BeginUploadPacket {
-    let file = File::open(file_path).expect("File should exist by now");
-    let Ok(metadata) = file.metadata() else {
-        eprintln!("Cannot read metadata of file at {file_path}");
-        return BeginUploadPacket::new_empty();
-    };
+fn read_upload_packet(transaction_id: u64, reply: RawPacket) -> std::io::Result<BeginUploadPacket> {
+    if reply.id != BeginUploadPacket::ID {
+        return Err(Error::new(ErrorKind::InvalidData, "Upload information was expected"));
+    }
+    check_transaction(BeginUploadPacket::from_bytes(&reply.content), transaction_id)
+}
+
+// An answer to another offer means both sides lost track of what's going on
+fn check_transaction(upload: BeginUploadPacket, transaction_id: u64) -> std::io::Result<BeginUploadPacket> {
+    if upload.transaction_id != transaction_id {
+        let message = format!("Offer {transaction_id} was answered for transaction {}", upload.transaction_id);
+        return Err(Error::new(ErrorKind::InvalidData, message));
+    }
+    Ok(upload)
+}
 
-    let offer = FileOfferPacket::new(1, metadata.len(), file_name.to_string());
-    let _ = offer.write_header(stream);
-    let _ = offer.write(stream);
+// The receiving side of a session, shared by the packet router and the read command
+struct Inbox {
+    // offers that weren't answered yet
+    offers: Mutex<VecDeque<Offer>>,
+    offer_arrived: Condvar,
+    incoming: Mutex<Downloads<Accepted>>,
+    // downloads cut off by a drop continue as soon as the sender offers them again
+    resume: Mutex<Vec<Resume>>,
+}
 
-    println!("Offered {file_name} file");
-    let id = packet::read_id(stream);
-    if id != BeginUploadPacket::ID {
-        eprintln!("Upload information was expected");
-        return BeginUploadPacket::new_empty();
+impl Inbox {
+    fn new() -> Self {
+        Self {
+            offers: Mutex::new(VecDeque::new()),
+            offer_arrived: Condvar::new(),
+            incoming: Mutex::new(Downloads::new()),
+            resume: Mutex::new(vec![]),
+        }
+    }
+
+    // Downloads in progress are recorded, unanswered offers are made again after reconnecting
+    fn interrupt(&self) {
+        self.offers.lock().unwrap().clear();
+        let interrupted = self.incoming.lock().unwrap().drain();
+        let mut resume = self.resume.lock().unwrap();
+        resume.extend(interrupted.into_iter().filter_map(|accepted| accepted.interrupted));
     }
-    let packet_size = packet::read_content_size(stream);
-    let field_buffer = packet::read_into_new_buffer(stream, packet_size);
 
-    BeginUploadPacket::from_bytes(&field_buffer)
+    fn take_resume(&self, transaction_id: u64) -> Option<Resume> {
+        let mut resume = self.resume.lock().unwrap();
+        let position = resume.iter().position(|interrupted| interrupted.transaction_id() == transaction_id)?;
+        Some(resume.remove(position))
+    }
 }
 
-fn read_and_handle_packet(stream: &mut TcpStream) {
-    let id = packet::read_id(stream);
-    let packet_size = packet::read_content_size(stream);
-    let field_buffer = packet::read_into_new_buffer(stream, packet_size);
+enum Offer {
+    File(FileOfferPacket),
+    Directory(DirectoryOfferPacket),
+}
 
-    match id {
-        FileOfferPacket::ID => {
-            let file_offer = match FileOfferPacket::construct(&field_buffer) {
-                Ok(fo) => fo,
-                Err(err) => {
-                    eprintln!("Failure: {err}");
-                    return;
-                }
-            };
+impl Offer {
+    fn parse(packet: &RawPacket) -> Result<Self, String> {
+        if packet.id == DirectoryOfferPacket::ID {
+            return Ok(Offer::Directory(DirectoryOfferPacket::from_bytes(&packet.content)));
+        }
+        FileOfferPacket::construct(&packet.content).map(Offer::File)
+    }
+
+    fn transaction_id(&self) -> u64 {
+        match self {
+            Offer::File(offer) => offer.transaction_id,
+            Offer::Directory(offer) => offer.transaction_id,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Offer::File(offer) => &offer.file_name,
+            Offer::Directory(offer) => &offer.directory_name,
+        }
+    }
+}
 
-            receive_file(file_offer, stream);
+// Reads everything the peer sends for as long as the session lasts, so that downloads, answers
+// to our offers and pings arrive while we upload. Once the connection breaks the downloads in
+// progress are recorded and the router waits for the session to reconnect
+fn route_session<'scope>(scope: &'scope thread::Scope<'scope, '_>, session: &'scope Session<'scope>, inbox: &'scope Inbox) {
+    while !session.is_closed() {
+        let Ok((mut reader, generation)) = session.reader() else {
+            session.close();
+            return;
+        };
+        let Err(err) = route_packets(scope, session, inbox, &mut reader) else {
+            return;
+        };
+        if session.is_closed() {
+            return;
         }
-        DirectoryOfferPacket::ID => {
-            let dir_offer = DirectoryOfferPacket::from_bytes(&field_buffer);
-            receive_directory(dir_offer, stream);
+        eprintln!("Connection was interrupted: {err}");
+        inbox.interrupt();
+        if !session.reconnect(generation) {
+            return;
         }
-        FilePacket::ID => {
-            match FilePacket::wrap(&field_buffer) {
-                Ok(packet) => {
-                    let transaction_id = packet.transaction_id;
-                    let chunk = packet.chunk_id;
-                    let content_len = packet.file_bytes.len();
-                    println!("File packet: chunk={chunk} content_len={content_len} | transaction_id:{transaction_id}")
+    }
+}
+
+fn route_packets<'scope>(scope: &'scope thread::Scope<'scope, '_>, session: &'scope Session<'scope>, inbox: &'scope Inbox, reader: &mut dyn Transport) -> std::io::Result<()> {
+    let mut reader = session.touching(reader);
+    while !session.is_closed() {
+        // read timeouts only mean the peer has nothing to send
+        let Some(packet) = packet::poll_packet(&mut reader)? else {
+            continue;
+        };
+        match packet.id {
+            HoleMapPacket::ID | FilePacket::ID => {
+                let completed = inbox.incoming.lock().unwrap().receive(packet.id, &packet.content, session)?;
+                if let Some(accepted) = completed {
+                    finish_download(accepted);
+                }
+            }
+            BeginUploadPacket::ID => {
+                let transaction_id = BeginUploadPacket::from_bytes(&packet.content).transaction_id;
+                session.deliver_reply(transaction_id, packet);
+            }
+            ArchiveRequestPacket::ID => {
+                let request = ArchiveRequestPacket::from_bytes(&packet.content)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                session.deliver_reply(request.transaction_id, packet);
+            }
+            ParallelStreamsPacket::ID => {
+                let announcement = ParallelStreamsPacket::from_bytes(&packet.content)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                let transaction_id = announcement.transaction_id;
+                if session.is_ours(transaction_id) {
+                    session.deliver_reply(transaction_id, packet);
+                    continue;
+                }
+                let files = inbox.incoming.lock().unwrap().files(transaction_id).map(<[TransferFile]>::to_vec);
+                let Some(files) = files else {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Streams were announced for unknown transaction {transaction_id}")));
+                };
+                let lanes = parallel::join_announced_lanes(session, &announcement, true)?;
+                if !lanes.is_empty() {
+                    scope.spawn(move || download_over_lanes(inbox, lanes, files, transaction_id));
                 }
-                Err(err) => eprintln!("Failure: {err}")
             }
+            FileOfferPacket::ID | DirectoryOfferPacket::ID => {
+                let offer = match Offer::parse(&packet) {
+                    Ok(offer) => offer,
+                    Err(err) => {
+                        eprintln!("Failure: {err}");
+                        continue;
+                    }
+                };
+                // resumed downloads are accepted without asking
+                if let Some(resumed) = inbox.take_resume(offer.transaction_id()) {
+                    answer_offer(offer, Some(resumed), session, inbox);
+                    continue;
+                }
+                println!("{} was offered, type read to answer", offer.name());
+                inbox.offers.lock().unwrap().push_back(offer);
+                inbox.offer_arrived.notify_all();
+            }
+            PingPacket::ID | SpeedtestInfoPacket::ID | SpeedPacket::ID => {
+                if packet.id == PingPacket::ID && !session.is_exchanging() {
+                    let taken = PingPacket::millis_taken(&packet.content);
+                    println!("Ping received after {taken}ms");
+                }
+                // kept for the rtt or speedtest command the peer expects us to run
+                session.deliver_exchange(packet);
+            }
+            // only keeps the session alive
+            HeartbeatPacket::ID => {}
+            ErrorPacket::ID => {
+                let error = ErrorPacket::from_bytes(&packet.content)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                let transaction_id = error.transaction_id;
+                if session.is_ours(transaction_id) {
+                    // whoever made the offer stops and reports it
+                    session.deliver_reply(transaction_id, packet);
+                    continue;
+                }
+                inbox.offers.lock().unwrap().retain(|offer| offer.transaction_id() != transaction_id);
+                inbox.take_resume(transaction_id);
+                let name = inbox.incoming.lock().unwrap().cancel(transaction_id);
+                let name = name.unwrap_or_else(|| format!("Transaction {transaction_id}"));
+                eprintln!("{name} failed on the peer's side: {}", error.message);
+            }
+            GoodbyePacket::ID => {
+                match GoodbyePacket::from_bytes(&packet.content) {
+                    Ok(goodbye) => println!("Session was closed, {}", goodbye.reason.describe()),
+                    Err(err) => println!("Session was closed by the peer ({err})"),
+                }
+                session.close();
+                return Ok(());
+            }
+            id => println!("Unrecognized packet {id}"),
         }
-        PingPacket::ID => {
-            let taken = PingPacket::millis_taken(&field_buffer);
-            println!("Ping received after {taken}ms");
+    }
+    Ok(())
+}
+
+// Runs next to the router, the uploader doesn't multiplex an upload that goes over parallel streams
+fn download_over_lanes(inbox: &Inbox, mut lanes: Vec<TcpStream>, files: Vec<TransferFile>, transaction_id: u64) {
+    let result = parallel::download(&mut lanes, &files, transaction_id);
+    let Some(accepted) = inbox.incoming.lock().unwrap().remove(transaction_id) else {
+        return;
+    };
+    match result {
+        Ok(_) => finish_download(accepted),
+        Err(err) => {
+            eprintln!("Download over streams couldn't complete: {err}");
+            inbox.resume.lock().unwrap().extend(accepted.interrupted);
         }
-        SpeedPacket::ID => {
-            // don't construct packet - waste of time
+    }
+}
+
+// The read command, answers the oldest offer or waits for the next one
+fn answer_next_offer(session: &Session, inbox: &Inbox) {
+    let mut offers = inbox.offers.lock().unwrap();
+    if offers.is_empty() {
+        println!("Waiting for an offer..");
+    }
+    let offer = loop {
+        if let Some(offer) = offers.pop_front() {
+            break offer;
         }
-        _ => {
-            println!("Unrecognized packet {id}");
+        if session.is_closed() {
+            return;
         }
+        offers = inbox.offer_arrived.wait_timeout(offers, SESSION_POLL).unwrap().0;
+    };
+    drop(offers);
+    answer_offer(offer, None, session, inbox);
+}
+
+// Accepted offers are added to the incoming downloads before they're answered,
+// their packets may arrive as soon as the answer is sent
+fn answer_offer(offer: Offer, resumed: Option<Resume>, session: &Session, inbox: &Inbox) {
+    let transaction_id = offer.transaction_id();
+    let result = match offer {
+        Offer::Directory(offer) => receive_directory(offer, resumed, session, inbox),
+        Offer::File(offer) if offer.is_stream() => receive_stream(offer, session, inbox),
+        Offer::File(offer) => receive_file(offer, resumed, session, inbox),
+    };
+    if let Err(err) = result {
+        eprintln!("Failed to answer the offer: {err}");
+        report_error(transaction_id, &err, session);
     }
 }
 
-fn receive_directory(offer: DirectoryOfferPacket, stream: &mut TcpStream) {
-    let total_size = util::format_size(offer.total_size);
-    println!("Download {} files to {}?  [{total_size}] (y/n)", offer.file_count, offer.directory_name);
-    if !util::read_line().starts_with('y') {
-        write_denied_packet(stream);
+// What's left of an accepted download, used once it's complete or cut off
+struct Accepted {
+    // streams and archives can't be resumed
+    interrupted: Option<Resume>,
+    // links of a directory are created once its files are on disk
+    directory: Option<DirectoryOfferPacket>,
+    // an archive being extracted while it downloads
+    extraction: Option<JoinHandle<()>>,
+}
+
+fn finish_download(accepted: Accepted) {
+    if let Some(extraction) = accepted.extraction {
+        let _ = extraction.join();
+    }
+    let Some(offer) = accepted.directory else {
         return;
+    };
+    create_links(&offer);
+    println!("Downloads:");
+    for file in offer.files {
+        println!("{} [{}]", file.name, util::format_size(file.size));
     }
+}
+
+fn receive_directory(offer: DirectoryOfferPacket, resumed: Option<Resume>, session: &Session, inbox: &Inbox) -> std::io::Result<()> {
+    let transaction_id = offer.transaction_id;
+    let resumed_indexes = resumed.and_then(|resumed| resumed.resumed_indexes(&offer));
+    let selected_indexes = if let Some(indexes) = resumed_indexes {
+        println!("Resuming {} files of {}", indexes.len(), offer.directory_name);
+        indexes
+    } else {
+        let total_size = util::format_size(offer.total_size);
+        println!("Download {} files to {}?  [{total_size}] (y/n, a - as a single archive, s - select files)", offer.file_count, offer.directory_name);
+        let answer = util::read_line();
+        if answer.starts_with('a') {
+            return receive_directory_archive(offer, session, inbox);
+        }
+        if answer.starts_with('s') {
+            match selection::select_files(&offer) {
+                Some(indexes) => indexes,
+                None => {
+                    deny_offer(transaction_id, session);
+                    return Ok(());
+                }
+            }
+        } else if answer.starts_with('y') {
+            offer.regular_file_indexes()
+        } else {
+            deny_offer(transaction_id, session);
+            return Ok(());
+        }
+    };
 
+    if !util::is_contained_path(&offer.directory_name) {
+        deny_invalid_name(transaction_id, &offer.directory_name, session);
+        return Ok(());
+    }
+    let interrupted = Resume::Directory(transaction_id, offer.directory_name.clone(), selected_indexes.clone());
     let dir_path = Path::new(&offer.directory_name);
 
     let upload = if dir_path.exists() {
         // Mark cursor positions per file
-        let Ok(fs_entries) = std::fs::read_dir(dir_path) else {
-            eprintln!("Cannot read directory, aborting");
-            write_denied_packet(stream);
-            return;
-        };
-
-        let mut fs_names: HashMap<String, u64> = HashMap::with_capacity(offer.file_count as usize);
-
-        for maybe_entry in fs_entries {
-            let Ok(entry) = maybe_entry else {
-                continue;
-            };
-            let path = entry.path();
-            if !path.is_file() {
-                continue
-            }
-            let entry_name = entry.file_name().to_str().unwrap().to_string();
-            let Ok(metadata) = path.metadata() else {
-                eprintln!("Skipping {entry_name}, unable to retrieve metadata");
-                continue
-            };
-            let size = metadata.len();
-            fs_names.insert(entry_name, size);
-        }
-
         let mut file_indexes: Vec<u32> = vec![];
         let mut cursors: Vec<u64> = vec![];
 
-        for (i, file) in offer.files.iter().enumerate() {
-            let Some(size) = fs_names.get(&file.name) else {
-                file_indexes.push(i as u32);
+        for i in selected_indexes {
+            let file = &offer.files[i as usize];
+            if !util::is_contained_path(&file.name) {
+                eprintln!("Skipping {}, invalid name", file.name);
+                continue;
+            }
+            let Ok(metadata) = dir_path.join(&file.name).metadata() else {
+                file_indexes.push(i);
                 cursors.push(0);
                 continue;
             };
-            if *size < file.size {
-                file_indexes.push(i as u32);
-                cursors.push(*size);
+            if metadata.is_file() && metadata.len() < file.size {
+                file_indexes.push(i);
+                cursors.push(metadata.len());
             }
         }
-        let accepted_upload = BeginUploadPacket::new(1, file_indexes, cursors);
-        let _ = accepted_upload.write_header(stream);
-        let _ = accepted_upload.write(stream);
-        accepted_upload
+        BeginUploadPacket::new(transaction_id, file_indexes, cursors)
     } else {
         match std::fs::create_dir(&offer.directory_name) {
             Ok(_) => println!("Directory created"),
             Err(err) => {
                 eprintln!("{err}");
-                return;
+                report_error(transaction_id, &err, session);
+                return Ok(());
             },
         };
-        let accepted_upload = BeginUploadPacket::accept_all(1, offer.file_count);
-        let _ = accepted_upload.write_header(stream);
-        let _ = accepted_upload.write(stream);
-        accepted_upload
+        let mut file_indexes = vec![];
+        for i in selected_indexes {
+            let file = &offer.files[i as usize];
+            if !util::is_contained_path(&file.name) {
+                eprintln!("Skipping {}, invalid name", file.name);
+                continue;
+            }
+            file_indexes.push(i);
+        }
+        let cursors = vec![0; file_indexes.len()];
+        BeginUploadPacket::new(transaction_id, file_indexes, cursors)
     };
 
     if !upload.has_any_files() {
+        let _ = session.send(&upload);
         println!("No files were accepted");
-        return;
+        create_links(&offer);
+        return Ok(());
     }
 
     println!("Accepting {} out of {} files", upload.files_accepted, offer.file_count);
+    let mut files = Vec::with_capacity(upload.file_indexes.len());
     for (i, index) in upload.file_indexes.iter().enumerate() {
         let file_offered = &offer.files[*index as usize];
-        let current_size = upload.cursors[i];
-        let relative_path = Path::new(&offer.directory_name).join(&file_offered.name);
-        let dest_file = if current_size > 0 {
-            OpenOptions::new().append(true).open(relative_path).unwrap()
-        } else {
-            File::create(relative_path).expect("Failed to create destination file!")
+        let path = Path::new(&offer.directory_name).join(&file_offered.name);
+        files.push(TransferFile { path, cursor: upload.cursors[i], size: file_offered.size });
+    }
+    let name = offer.directory_name.clone();
+    let accepted = Accepted { interrupted: Some(interrupted), directory: Some(offer), extraction: None };
+    inbox.incoming.lock().unwrap().add(transaction_id, name, files, accepted);
+    let _ = session.send(&upload);
+    Ok(())
+}
+
+// Archives are streamed so they can't be resumed
+fn receive_directory_archive(offer: DirectoryOfferPacket, session: &Session, inbox: &Inbox) -> std::io::Result<()> {
+    let transaction_id = offer.transaction_id;
+    println!("Archive format? (tar/gz)");
+    let format = if util::read_line().starts_with('g') { ArchiveFormat::TarGz } else { ArchiveFormat::Tar };
+    println!("Extract while downloading? (y/n)");
+    let extract = util::read_line().starts_with('y');
+
+    if !util::is_contained_path(&offer.directory_name) {
+        deny_invalid_name(transaction_id, &offer.directory_name, session);
+        return Ok(());
+    }
+    let archive_name = format!("{}.{}", offer.directory_name, format.extension());
+    let destination: std::io::Result<(Box<dyn Write + Send>, _)> = if extract {
+        std::fs::create_dir_all(&offer.directory_name).map(|_| {
+            let (sink, extraction) = archive::extract_in_background(format, PathBuf::from(&offer.directory_name));
+            (Box::new(sink) as _, Some(extraction))
+        })
+    } else {
+        File::create(&archive_name).map(|file| (Box::new(BufWriter::new(file)) as _, None))
+    };
+    let (sink, extraction) = match destination {
+        Ok(destination) => destination,
+        Err(err) => {
+            eprintln!("{err}");
+            report_error(transaction_id, &err, session);
+            return Ok(());
+        }
+    };
+    let accepted = Accepted { interrupted: None, directory: None, extraction };
+    inbox.incoming.lock().unwrap().add_stream(transaction_id, archive_name, sink, accepted);
+    let _ = session.send(&ArchiveRequestPacket::new(transaction_id, format));
+    Ok(())
+}
+
+// Links are created once the files they may refer to are on disk
+fn create_links(offer: &DirectoryOfferPacket) {
+    let dir_path = Path::new(&offer.directory_name);
+    for file in &offer.files {
+        if file.is_file() {
+            continue;
+        }
+        if !util::is_contained_path(&file.name) {
+            eprintln!("Skipping link {}, invalid name", file.name);
+            continue;
+        }
+        let link_path = dir_path.join(&file.name);
+        if link_path.symlink_metadata().is_ok() {
+            continue;
+        }
+        if let Some(parent) = link_path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let result = match file.kind {
+            EntryKind::Symlink => {
+                // the target is resolved against the directory the link is in
+                let link_parent = Path::new(&file.name).parent().unwrap_or(Path::new(""));
+                let resolved_target = link_parent.join(&file.link_target);
+                if !util::is_contained_path(resolved_target.to_str().unwrap_or("")) {
+                    eprintln!("Skipping symlink {} -> {}, target is outside of the directory", file.name, file.link_target);
+                    continue;
+                }
+                util::create_symlink(&file.link_target, &link_path)
+            }
+            EntryKind::Hardlink => {
+                let is_offered_file = offer.files.iter().any(|f| f.is_file() && f.name == file.link_target);
+                if !is_offered_file || !util::is_contained_path(&file.link_target) {
+                    eprintln!("Skipping hardlink {}, {} is not an offered file", file.name, file.link_target);
+                    continue;
+                }
+                if !dir_path.join(&file.link_target).exists() {
+                    println!("Skipping hardlink {}, {} wasn't downloaded", file.name, file.link_target);
+                    continue;
+                }
+                std::fs::hard_link(dir_path.join(&file.link_target), &link_path)
+            }
+            EntryKind::File => continue,
         };
-        read_and_write_file_to_disk(current_size, file_offered.size, dest_file, stream);
-        println!("Received {}/{} files", i+1, offer.file_count);
+        match result {
+            Ok(_) => println!("Linked {} -> {}", file.name, file.link_target),
+            Err(err) => eprintln!("Failed to create link {}: {err}", file.name),
+        }
     }
-    println!("Downloads:");
-    for file in offer.files {
-        println!("{} [{}]", file.name, util::format_size(file.size));
+}
+
+// A download cut off by a dropped connection, when the sender offers the same transaction
+// again after reconnecting it's accepted without asking and continues from the on-disk size.
+// Only the same offer is resumed, anything else under that transaction is asked about as usual
+pub enum Resume {
+    // along with the name and size offered
+    File(u64, String, u64),
+    // along with the directory's name and the indexes of the files that were accepted
+    Directory(u64, String, Vec<u32>),
+}
+
+impl Resume {
+    fn transaction_id(&self) -> u64 {
+        match self {
+            Resume::File(id, ..) | Resume::Directory(id, ..) => *id,
+        }
+    }
+
+    pub fn resumes_file(&self, offer: &FileOfferPacket) -> bool {
+        matches!(self, Resume::File(_, name, size) if *name == offer.file_name && *size == offer.file_size)
     }
 
+    // The accepted files have to be regular files of the new offer too
+    pub fn resumed_indexes(self, offer: &DirectoryOfferPacket) -> Option<Vec<u32>> {
+        let Resume::Directory(_, name, indexes) = self else {
+            return None;
+        };
+        let still_files = indexes.iter().all(|&i| offer.files.get(i as usize).is_some_and(|file| file.is_file()));
+        (name == offer.directory_name && still_files).then_some(indexes)
+    }
 }
 
-fn receive_file(file_offer: FileOfferPacket, stream: &mut TcpStream) {
+fn receive_file(file_offer: FileOfferPacket, resumed: Option<Resume>, session: &Session, inbox: &Inbox) -> std::io::Result<()> {
+    let transaction_id = file_offer.transaction_id;
+    if !util::is_contained_path(&file_offer.file_name) {
+        deny_invalid_name(transaction_id, &file_offer.file_name, session);
+        return Ok(());
+    }
+    let resumed = resumed.is_some_and(|resumed| resumed.resumes_file(&file_offer));
     let path = Path::new(&file_offer.file_name);
     let mut current_size = 0;
     // Resume download from cursor pos
     if path.exists() {
-        current_size = path.metadata().unwrap().len();
+        current_size = path.metadata()?.len();
         if current_size >= file_offer.file_size {
-            write_denied_packet(stream);
+            deny_offer(transaction_id, session);
             eprintln!("Denied offer because current size >= offered");
-            return;
+            return Ok(());
         }
         let remaining = util::format_size(file_offer.file_size - current_size);
-        println!("Resume downloading {}? {remaining} remaining (y/n)", file_offer.file_name);
-        if util::read_line().starts_with('y') {
-            let accept_upload = BeginUploadPacket::single_file(file_offer.transaction_id, current_size);
-            let _ = accept_upload.write_header(stream);
-            let _ = accept_upload.write(stream);
+        if resumed {
+            println!("Resuming {}, {remaining} remaining", file_offer.file_name);
         } else {
-            write_denied_packet(stream);
-            return;
+            println!("Resume downloading {}? {remaining} remaining (y/n)", file_offer.file_name);
+            if !util::read_line().starts_with('y') {
+                deny_offer(transaction_id, session);
+                return Ok(());
+            }
         }
     } else {
-        let offer_size = util::format_size(file_offer.file_size);
-        println!("Download {}?  [{offer_size}] (y/n)", file_offer.file_name);
-        if util::read_line().starts_with('y') {
-            if let Err(err) = File::create(&file_offer.file_name) {
-                eprintln!("{err}");
-                write_denied_packet(stream);
-                return;
+        if !resumed {
+            let offer_size = util::format_size(file_offer.file_size);
+            println!("Download {}?  [{offer_size}] (y/n)", file_offer.file_name);
+            if !util::read_line().starts_with('y') {
+                deny_offer(transaction_id, session);
+                return Ok(());
             }
-            let accept_upload = BeginUploadPacket::single_file(file_offer.transaction_id, current_size);
-            let _ = accept_upload.write_header(stream);
-            let _ = accept_upload.write(stream);
-        } else {
-            write_denied_packet(stream);
-            return;
+        }
+        if let Err(err) = File::create(&file_offer.file_name) {
+            eprintln!("{err}");
+            report_error(transaction_id, &err, session);
+            return Ok(());
         }
     }
-    let file = OpenOptions::new()
-        .append(true)
-        .open(file_offer.file_name).unwrap();
-    read_and_write_file_to_disk(current_size, file_offer.file_size, file, stream);
+    let path = PathBuf::from(&file_offer.file_name);
+    let file = TransferFile { path, cursor: current_size, size: file_offer.file_size };
+    let interrupted = Resume::File(transaction_id, file_offer.file_name.clone(), file_offer.file_size);
+    let accepted = Accepted { interrupted: Some(interrupted), directory: None, extraction: None };
+    inbox.incoming.lock().unwrap().add(transaction_id, file_offer.file_name, vec![file], accepted);
+    let _ = session.send(&BeginUploadPacket::single_file(transaction_id, current_size));
+    Ok(())
+}
+
+// Streams can't be resumed, an existing file is overwritten
+fn receive_stream(file_offer: FileOfferPacket, session: &Session, inbox: &Inbox) -> std::io::Result<()> {
+    let transaction_id = file_offer.transaction_id;
+    if !util::is_contained_path(&file_offer.file_name) {
+        deny_invalid_name(transaction_id, &file_offer.file_name, session);
+        return Ok(());
+    }
+    let overwrite = if Path::new(&file_offer.file_name).exists() { " (overwrite)" } else { "" };
+    println!("Download stream into {}{overwrite}?  [unknown size] (y/n)", file_offer.file_name);
+    if !util::read_line().starts_with('y') {
+        deny_offer(transaction_id, session);
+        return Ok(());
+    }
+    let file = match File::create(&file_offer.file_name) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("{err}");
+            report_error(transaction_id, &err, session);
+            return Ok(());
+        }
+    };
+    let accepted = Accepted { interrupted: None, directory: None, extraction: None };
+    inbox.incoming.lock().unwrap().add_stream(transaction_id, file_offer.file_name, Box::new(BufWriter::new(file)), accepted);
+    let _ = session.send(&BeginUploadPacket::single_file(transaction_id, 0));
+    Ok(())
 }
 
-fn read_and_write_file_to_disk(mut current_size: u64, total_size: u64, mut file: File, stream: &mut TcpStream) {
+// Errors mean the connection is broken or out of sync, the file can be resumed from its size
+pub fn read_and_write_file_to_disk<W: HoleWriter>(mut current_size: u64, total_size: u64, mut file: W, transaction_id: u64, stream: &mut dyn Transport) -> std::io::Result<()> {
+    let id = packet::read_id(stream)?;
+    if id != HoleMapPacket::ID {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{id} wasn't expected at this time, hole map was expected")));
+    }
+    let packet_size = packet::read_content_size(stream)?;
+    let field_buffer = packet::read_into_new_buffer(stream, packet_size)?;
+    let hole_map = match HoleMapPacket::from_bytes(&field_buffer) {
+        Ok(hole_map) => hole_map,
+        Err(err) => return Err(Error::new(ErrorKind::InvalidData, format!("Error at HoleMapPacket::from_bytes - {err}"))),
+    };
+    if hole_map.transaction_id != transaction_id {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Hole map of transaction {} wasn't expected", hole_map.transaction_id)));
+    }
+    let mut holes = hole_map.holes.iter().peekable();
+
     let mut buffer = vec![0u8; MB_1];
     let mut bytes_read = 0;
     let mut expected_chunk_id = 0;
     // Begin reading file packets
     let start = Instant::now();
     while current_size < total_size {
-        let id = packet::read_id(stream);
+        // Extending the file leaves a hole in place of the skipped bytes
+        if let Some((_, length)) = holes.next_if(|(offset, _)| *offset == current_size) {
+            current_size += length;
+            file.write_hole(current_size, *length)?;
+            continue;
+        }
+        let id = packet::read_id(stream)?;
         if id != FilePacket::ID {
-            eprintln!("{id} wasn't expected at this time");
-            return;
+            return Err(Error::new(ErrorKind::InvalidData, format!("{id} wasn't expected at this time")));
         }
-        let content_size = packet::read_content_size(stream) as usize;
+        let content_size = packet::read_content_size(stream)? as usize;
         if content_size > buffer.len() {
             buffer.reserve_exact(content_size - buffer.len());
         }
         unsafe { buffer.set_len(content_size); }
-        if packet::tcp_read_safe(&mut buffer, stream).is_err() {
-            eprintln!("Terminating read since buffer couldn't be filled");
-            let _ = stream.shutdown(Shutdown::Read);
-            return;
-        }
+        packet::tcp_read_safe(&mut buffer, stream)?;
         let packet = match FilePacket::wrap(&buffer) {
             Ok(file_packet) => file_packet,
             Err(err) => {
-                eprintln!("Error at FilePacket::wrap - {err}");
-                eprintln!("Terminating read since packet was made incorrectly..");
                 let _ = stream.shutdown(Shutdown::Read);
-                return;
+                return Err(Error::new(ErrorKind::InvalidData, format!("Error at FilePacket::wrap - {err}")));
             }
         };
 
-        if packet.chunk_id != expected_chunk_id {
-            eprintln!("Terminating read to avoid file corruption (packet was skipped)");
+        if packet.transaction_id != transaction_id || packet.chunk_id != expected_chunk_id {
             let _ = stream.shutdown(Shutdown::Read);
-            return;
+            return Err(Error::new(ErrorKind::InvalidData, "Terminating read to avoid file corruption (packet was skipped)"));
         }
 
         let content_len = packet.file_bytes.len() as u64;
-        while let Err(err) = file.write_all(packet.file_bytes) {
-            eprintln!("Failed to write to file: {err}")
-        }
+        file.write_all(packet.file_bytes)?;
         current_size += content_len;
         bytes_read += content_len;
         expected_chunk_id += 1;
@@ -428,63 +1703,163 @@ fn read_and_write_file_to_disk(mut current_size: u64, total_size: u64, mut file:
         let speed = bytes_read as f64 / MB_1 as f64 / seconds_so_far;
         let progress = (current_size as f64 / total_size as f64) * 100.0;
         let eta = util::format_eta(current_size, total_size, speed);
-        eprintln!("progress={progress:.2}% ({speed:.2}MB/s) ETA: {eta}");
+        eprintln!("progress={progress:.2}% ({speed:.2}MB/s{}) ETA: {eta}", throttle::cap_suffix());
         buffer.clear();
     }
+    let _ = file.flush();
     let elapsed = start.elapsed().as_secs_f64();
     let time_format = util::format_time(elapsed);
-    println!("Download completed in {time_format}");
+    eprintln!("Download completed in {time_format}");
+    Ok(())
+}
+
+// Reads file packets of a stream offer until the end of stream packet
+fn read_stream_to_writer<W: Write>(mut writer: W, transaction_id: u64, stream: &mut dyn Transport) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; MB_1];
+    let mut bytes_read = 0;
+    let mut expected_chunk_id = 0;
+    let start = Instant::now();
+    loop {
+        let id = packet::read_id(stream)?;
+        if id != FilePacket::ID {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{id} wasn't expected at this time")));
+        }
+        let content_size = packet::read_content_size(stream)? as usize;
+        buffer.resize(content_size, 0);
+        packet::tcp_read_safe(&mut buffer, stream)?;
+        let packet = match FilePacket::wrap(&buffer) {
+            Ok(file_packet) => file_packet,
+            Err(err) => {
+                let _ = stream.shutdown(Shutdown::Read);
+                return Err(Error::new(ErrorKind::InvalidData, format!("Error at FilePacket::wrap - {err}")));
+            }
+        };
+        if packet.transaction_id != transaction_id || packet.chunk_id != expected_chunk_id {
+            let _ = stream.shutdown(Shutdown::Read);
+            return Err(Error::new(ErrorKind::InvalidData, "Terminating read to avoid stream corruption (packet was skipped)"));
+        }
+        if packet.is_end_of_stream() {
+            break;
+        }
+        if let Err(err) = writer.write_all(packet.file_bytes) {
+            eprintln!("Failed to write stream: {err}");
+            let _ = stream.shutdown(Shutdown::Read);
+            return Err(err);
+        }
+        bytes_read += packet.file_bytes.len() as u64;
+        expected_chunk_id += 1;
+        let speed = bytes_read as f64 / MB_1 as f64 / start.elapsed().as_secs_f64();
+        eprintln!("received={} ({speed:.2}MB/s{})", util::format_size(bytes_read), throttle::cap_suffix());
+    }
+    let _ = writer.flush();
+    let time_format = util::format_time(start.elapsed().as_secs_f64());
+    eprintln!("Stream of {} completed in {time_format}", util::format_size(bytes_read));
+    Ok(())
 }
 
-fn stream_file(path: &str, mut cursor: u64, stream: &mut TcpStream) {
-    let mut file_feeder = FileFeeder::new(path, MB_1).expect("Couldn't initialize file reader");
+pub fn stream_file(path: &str, mut cursor: u64, transaction_id: u64, stream: &mut dyn Transport) -> std::io::Result<()> {
+    let mut file_feeder = FileFeeder::new(path, MB_1)?;
     file_feeder.set_cursor_pos(cursor);
     let size_goal = file_feeder.file_size();
+    let hole_map = HoleMapPacket::new(transaction_id, file_feeder.holes_from(cursor));
+    hole_map.write_header(stream).and(hole_map.write(stream))?;
     let mut bytes_written: u64 = 0;
     let mut chunk_id = 0;
     let start = Instant::now();
     while file_feeder.has_next_chunk() {
-        let chunk = file_feeder.read_next_chunk().expect("No next chunk");
-        let packet = FilePacket::new(1, chunk_id, chunk);
-        if packet.write_header(stream).and(packet.write(stream)).is_err() {
+        let chunk = file_feeder.read_next_chunk()?;
+        let packet = FilePacket::new(transaction_id, chunk_id, chunk);
+        if let Err(err) = packet.write_header(stream).and(packet.write(stream)) {
             println!("Upload couldn't complete");
-            break;
+            return Err(err);
         }
 
         chunk_id += 1;
         bytes_written += chunk.len() as u64;
-        cursor += chunk.len() as u64;
+        cursor = file_feeder.cursor_pos();
         let seconds_so_far = start.elapsed().as_secs_f64();
         let speed = bytes_written as f64 / MB_1 as f64 / seconds_so_far;
         let progress = (cursor as f64 / size_goal as f64) * 100.0;
         let eta = util::format_eta(cursor, size_goal, speed);
-        eprintln!("progress={progress:.2}% ({speed:.2}MB/s) ETA: {eta}");
+        eprintln!("progress={progress:.2}% ({speed:.2}MB/s{}) ETA: {eta}", throttle::cap_suffix());
     }
 
     let elapsed = start.elapsed().as_secs_f64();
     let time_format = util::format_time(elapsed);
     println!("Upload completed in {time_format}");
+    Ok(())
 }
 
-pub fn write_ping(stream: &mut TcpStream) {
-    let ping = PingPacket::new_ping();
-    let _ = ping.write_header(stream);
-    let _ = ping.write(stream);
+fn stream_reader<R: Read>(reader: R, transaction_id: u64, control: &dyn Control) {
+    let mut feeder = StreamFeeder::new(reader, MB_1);
+    let mut bytes_written: u64 = 0;
+    let mut chunk_id = 0;
+    let start = Instant::now();
+    while feeder.has_next_chunk() {
+        let chunk = match feeder.read_next_chunk() {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                eprintln!("Failed to read input: {err}");
+                break;
+            }
+        };
+        // an empty chunk is the end of stream packet
+        if chunk.is_empty() {
+            break;
+        }
+        if control.send(&FilePacket::new(transaction_id, chunk_id, chunk)).is_err() {
+            eprintln!("Upload couldn't complete");
+            return;
+        }
+        chunk_id += 1;
+        bytes_written += chunk.len() as u64;
+        let speed = bytes_written as f64 / MB_1 as f64 / start.elapsed().as_secs_f64();
+        eprintln!("sent={} ({speed:.2}MB/s{})", util::format_size(bytes_written), throttle::cap_suffix());
+    }
+    if control.send(&FilePacket::end_of_stream(transaction_id, chunk_id)).is_err() {
+        eprintln!("Upload couldn't complete");
+        return;
+    }
+    let time_format = util::format_time(start.elapsed().as_secs_f64());
+    println!("Upload of {} completed in {time_format}", util::format_size(bytes_written));
 }
 
-fn read_ping(stream: &mut TcpStream) {
-    let id = packet::read_id(stream);
-    let packet_size = packet::read_content_size(stream);
-
-    if id != PingPacket::ID {
-        eprintln!("ID {id} wasn't expected at this time");
-    }
-    let mut field_buffer = vec![0u8; packet_size as usize];
-    let _ = packet::tcp_read_safe(&mut field_buffer, stream);
+fn write_error_packet(error: ErrorPacket, stream: &mut dyn Transport) {
+    let _ = error.write_header(stream);
+    let _ = error.write(stream);
 }
 
-pub fn write_denied_packet(stream: &mut TcpStream) {
-    let denied_packet = BeginUploadPacket::new_empty();
+pub fn write_denied_packet(transaction_id: u64, stream: &mut dyn Transport) {
+    let denied_packet = BeginUploadPacket::denied(transaction_id);
     let _ = denied_packet.write_header(stream);
     let _ = denied_packet.write(stream);
-}
\ No newline at end of file
+}
+
+// The greeting is read first, closing a socket with unread data resets the connection
+// and the goodbye could be lost along with it
+fn reject_connection(stream: &mut dyn Transport) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+    let _ = packet::poll_packet(stream);
+    write_goodbye(GoodbyeReason::Rejected, stream);
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+fn write_goodbye(reason: GoodbyeReason, stream: &mut dyn Transport) {
+    let goodbye = GoodbyePacket::new(reason);
+    let _ = goodbye.write_header(stream);
+    let _ = goodbye.write(stream);
+}
+
+fn deny_offer(transaction_id: u64, control: &dyn Control) {
+    let _ = control.send(&BeginUploadPacket::denied(transaction_id));
+}
+
+// Tells the peer why its offer or transfer was given up on
+fn report_error(transaction_id: u64, err: &Error, control: &dyn Control) {
+    let _ = control.send(&ErrorPacket::from_error(transaction_id, err));
+}
+
+fn deny_invalid_name(transaction_id: u64, name: &str, control: &dyn Control) {
+    eprintln!("Denied offer, invalid name {name}");
+    let _ = control.send(&ErrorPacket::new(transaction_id, ErrorCode::InvalidName, format!("Invalid name {name}")));
+}
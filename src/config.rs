@@ -1,9 +1,46 @@
-use std::net::TcpStream;
 use std::time::Duration;
+use crate::access::{self, Cidr, Gatekeeper};
+use crate::transport::Transport;
+use crate::util;
 
 const HOST_AUTO_ACCEPT: &str = "host_auto_accept";
+const FOLLOW_SYMLINKS: &str = "follow_symlinks";
+// extra connections opened for every upload
+const PARALLEL_STREAMS: &str = "parallel_streams";
+// bytes per second in each direction, e.g. 20M
+const RATE_LIMIT: &str = "rate_limit";
+// how many times a dropped connection is dialed again before giving up
+const RECONNECT_ATTEMPTS: &str = "reconnect_attempts";
+// seconds without hearing from the peer before the session is closed, 0 never closes it
+const IDLE_TIMEOUT: &str = "idle_timeout";
+const DEFAULT_IDLE_TIMEOUT: u32 = 30;
+// a few heartbeats fit in even the shortest one, see cli::HEARTBEAT_INTERVAL
+const MIN_IDLE_TIMEOUT: u32 = 15;
+// path of a unix socket to host on or connect to instead of an ip and port
+const UNIX_SOCKET: &str = "unix_socket";
+// whether a host answers discovery probes and the name it answers with
+const DISCOVERABLE: &str = "discoverable";
+const NAME: &str = "name";
+// host:port of a relay both peers can reach and the token that pairs them there
+const RELAY: &str = "relay";
+const RELAY_TOKEN: &str = "relay_token";
+// socks5://[user:password@]host:port or http://[user:password@]host:port outgoing connections go through
+const PROXY: &str = "proxy";
+// address ranges a host lets connect (all by default) and the ones it turns away, e.g. 192.168.1.0/24, 10.0.0.7
+const ALLOW: &str = "allow";
+const DENY: &str = "deny";
+// connections a host takes from one address per minute, 0 - no limit
+const CONNECTIONS_PER_MINUTE: &str = "connections_per_minute";
+const DEFAULT_CONNECTIONS_PER_MINUTE: u32 = 20;
+// failed handshakes in a row that get an address banned and for how many minutes, 0 - no bans
+const BAN_AFTER_FAILURES: &str = "ban_after_failures";
+const DEFAULT_BAN_AFTER_FAILURES: u32 = 5;
+const BAN_MINUTES: &str = "ban_minutes";
+const DEFAULT_BAN_MINUTES: u32 = 10;
 
 const HOST_IP: &str = "host";
+// interface the host ip is taken from (e.g. wlp3s0), "all" or "all6" bind every interface
+const HOST_INTERFACE: &str = "host_interface";
 const HOST_PORT: &str = "host_port";
 const CONNECT_IP: &str = "connect";
 const CONNECT_PORT: &str = "connect_port";
@@ -22,11 +59,28 @@ const CONFIG_NAME: &str = "config.txt";
 pub struct Config {
     pub host_ip: Option<String>,
     pub host_port: Option<u16>,
+    pub host_interface: Option<String>,
     pub connect_ip: Option<String>,
     pub connect_port: Option<u16>,
     pub write_timeout: Option<u32>,
     pub read_timeout: Option<u32>,
     pub auto_accept: Option<bool>,
+    pub follow_symlinks: Option<bool>,
+    pub parallel_streams: Option<u8>,
+    pub rate_limit: Option<u64>,
+    pub reconnect_attempts: Option<u32>,
+    pub idle_timeout: Option<u32>,
+    pub unix_socket: Option<String>,
+    pub discoverable: Option<bool>,
+    pub name: Option<String>,
+    pub relay: Option<String>,
+    pub relay_token: Option<String>,
+    pub proxy: Option<String>,
+    pub allow: Option<Vec<Cidr>>,
+    pub deny: Option<Vec<Cidr>>,
+    pub connections_per_minute: Option<u32>,
+    pub ban_after_failures: Option<u32>,
+    pub ban_minutes: Option<u32>,
 }
 
 impl Config {
@@ -34,11 +88,28 @@ impl Config {
         Self {
             host_ip: None,
             host_port: None,
+            host_interface: None,
             connect_ip: None,
             connect_port: None,
             write_timeout: None,
             read_timeout: None,
             auto_accept: None,
+            follow_symlinks: None,
+            parallel_streams: None,
+            rate_limit: None,
+            reconnect_attempts: None,
+            idle_timeout: None,
+            unix_socket: None,
+            discoverable: None,
+            name: None,
+            relay: None,
+            relay_token: None,
+            proxy: None,
+            allow: None,
+            deny: None,
+            connections_per_minute: None,
+            ban_after_failures: None,
+            ban_minutes: None,
         }
     }
     pub fn read_config() -> Config {
@@ -63,9 +134,26 @@ impl Config {
             match key {
                 HOST_IP => config.host_ip = Some(value_str.to_string()),
                 CONNECT_IP => config.connect_ip = Some(value_str.to_string()),
+                HOST_INTERFACE => config.host_interface = Some(value_str.to_string()),
                 HOST_PORT => config.host_port = Some(value_str.parse::<u16>().unwrap()),
                 CONNECT_PORT => config.connect_port = Some(value_str.parse::<u16>().unwrap()),
                 HOST_AUTO_ACCEPT => config.auto_accept = Some(value_str.parse::<bool>().unwrap()),
+                FOLLOW_SYMLINKS => config.follow_symlinks = Some(value_str.parse::<bool>().unwrap()),
+                PARALLEL_STREAMS => config.parallel_streams = Some(value_str.parse::<u8>().unwrap()),
+                RATE_LIMIT => config.rate_limit = Some(util::parse_size(value_str).expect("Invalid rate_limit")),
+                RECONNECT_ATTEMPTS => config.reconnect_attempts = Some(value_str.parse::<u32>().unwrap()),
+                IDLE_TIMEOUT => config.idle_timeout = Some(value_str.parse::<u32>().unwrap()),
+                UNIX_SOCKET => config.unix_socket = Some(value_str.to_string()),
+                DISCOVERABLE => config.discoverable = Some(value_str.parse::<bool>().unwrap()),
+                NAME => config.name = Some(value_str.to_string()),
+                RELAY => config.relay = Some(value_str.to_string()),
+                RELAY_TOKEN => config.relay_token = Some(value_str.to_string()),
+                PROXY => config.proxy = Some(value_str.to_string()),
+                ALLOW => config.allow = Some(access::parse_rules(value_str).expect("Invalid allow")),
+                DENY => config.deny = Some(access::parse_rules(value_str).expect("Invalid deny")),
+                CONNECTIONS_PER_MINUTE => config.connections_per_minute = Some(value_str.parse::<u32>().unwrap()),
+                BAN_AFTER_FAILURES => config.ban_after_failures = Some(value_str.parse::<u32>().unwrap()),
+                BAN_MINUTES => config.ban_minutes = Some(value_str.parse::<u32>().unwrap()),
                 READ_TIMEOUT => config.read_timeout = Some(value_str.parse::<u32>().unwrap()),
                 WRITE_TIMEOUT => config.write_timeout = Some(value_str.parse::<u32>().unwrap()),
                 _ => {}
@@ -74,7 +162,7 @@ impl Config {
         config
     }
 
-    pub fn apply_timeouts(&self, stream: &mut TcpStream) {
+    pub fn apply_timeouts(&self, stream: &dyn Transport) {
         if let Some(seconds) = self.write_timeout {
             let timeout = Some(Duration::from_secs(seconds as u64));
             let _ = stream.set_write_timeout(timeout);
@@ -84,4 +172,33 @@ impl Config {
             let _ = stream.set_read_timeout(timeout);
         }
     }
+
+    pub fn follows_symlinks(&self) -> bool {
+        self.follow_symlinks.unwrap_or(false)
+    }
+
+    pub fn parallel_streams(&self) -> u8 {
+        self.parallel_streams.unwrap_or(0)
+    }
+
+    pub fn is_discoverable(&self) -> bool {
+        self.discoverable.unwrap_or(true)
+    }
+
+    pub fn gatekeeper(&self) -> Gatekeeper {
+        Gatekeeper::new(
+            self.allow.clone().unwrap_or_default(),
+            self.deny.clone().unwrap_or_default(),
+            self.connections_per_minute.unwrap_or(DEFAULT_CONNECTIONS_PER_MINUTE),
+            self.ban_after_failures.unwrap_or(DEFAULT_BAN_AFTER_FAILURES),
+            Duration::from_secs(self.ban_minutes.unwrap_or(DEFAULT_BAN_MINUTES) as u64 * 60),
+        )
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds.max(MIN_IDLE_TIMEOUT) as u64)),
+        }
+    }
 }
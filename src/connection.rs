@@ -1,6 +1,13 @@
-use std::io::Result;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::time::Duration;
+use crate::config::Config;
+use crate::proxy::Proxy;
+use crate::transport::{Listener, Transport, Tunneled};
 
 const TIMEOUT: Duration = Duration::from_secs(20);
 
@@ -15,9 +22,100 @@ pub fn connect_to_address(port: u16) -> Result<TcpStream> {
     TcpStream::connect(address)
 }
 
-pub(crate) fn connect_ipv4(server: &str, port: u16) -> Result<TcpStream> {
-    let socket = create_ipv4_socket(server, port);
-    TcpStream::connect_timeout(&socket, TIMEOUT)
+// A hostname can resolve to several addresses (e.g. IPv6 and IPv4), each gets the full timeout
+pub(crate) fn connect_tcp(server: &str, port: Option<u16>) -> Result<TcpStream> {
+    let mut last_err = None;
+    for address in resolve(server, port)? {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+// Addresses may carry their own port: "host", "host:80", "10.0.0.3", "::1", "[::1]" or "[::1]:80"
+pub fn split_port(address: &str, default_port: Option<u16>) -> Result<(&str, u16)> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("The address {address} is invalid"));
+    let (host, port) = if let Some(bracketed) = address.strip_prefix('[') {
+        let (host, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+        }
+    } else {
+        match address.split_once(':') {
+            // more than one colon is a bare IPv6 address
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (address, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse::<u16>().map_err(|_| invalid())?,
+        None => default_port.ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("No port given for {address}")))?,
+    };
+    Ok((host, port))
+}
+
+pub fn resolve(address: &str, default_port: Option<u16>) -> Result<Vec<SocketAddr>> {
+    let (host, port) = split_port(address, default_port)?;
+    let addresses: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    if addresses.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{address} didn't resolve to any address")));
+    }
+    Ok(addresses)
+}
+
+// What the connecting side dials, for messages
+pub fn peer_address(config: &Config) -> Option<String> {
+    if let Some(path) = &config.unix_socket {
+        return Some(path.clone());
+    }
+    let (host, port) = split_port(config.connect_ip.as_ref()?, config.connect_port).ok()?;
+    Some(join_host_port(host, port))
+}
+
+// IPv6 hosts are bracketed
+pub fn join_host_port(host: &str, port: u16) -> String {
+    match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    }
+}
+
+// Dials the peer from the config, over a unix socket if one is set
+pub fn connect(config: &Config) -> Result<Box<dyn Transport>> {
+    if let Some(path) = &config.unix_socket {
+        return connect_unix(path);
+    }
+    let Some(address) = &config.connect_ip else {
+        return Err(Error::new(ErrorKind::InvalidInput, "No address to connect to"));
+    };
+    match &config.proxy {
+        Some(proxy) => Ok(Box::new(Tunneled(connect_through(address, config.connect_port, Some(proxy))?))),
+        None => Ok(Box::new(connect_tcp(address, config.connect_port)?)),
+    }
+}
+
+// Through the proxy if there is one
+pub fn connect_through(address: &str, default_port: Option<u16>, proxy: Option<&str>) -> Result<TcpStream> {
+    let stream = match proxy {
+        Some(proxy) => Proxy::parse(proxy)?.connect(address, default_port)?,
+        None => connect_tcp(address, default_port)?,
+    };
+    // the proxy tunnel hides the socket from the session, which sets this otherwise
+    let _ = stream.set_nodelay(true);
+    Ok(stream)
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> Result<Box<dyn Transport>> {
+    Ok(Box::new(UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> Result<Box<dyn Transport>> {
+    Err(Error::new(ErrorKind::Unsupported, "Unix sockets aren't supported on this platform"))
 }
 
 pub fn receive_connection(address: &str) -> Result<TcpStream> {
@@ -33,26 +131,32 @@ pub fn receive_connection_at_port(address: &str, port: u16) -> Result<TcpStream>
     listener.incoming().next().unwrap()
 }
 
-pub fn create_server(address: &str, port: u16) -> TcpListener {
-    let full_address = &format!("{address}:{port}");
+pub fn create_server(address: &str, port: Option<u16>) -> TcpListener {
+    let addresses = resolve(address, port).unwrap_or_else(|err| panic!("Failed to create server - {err}"));
     // Binding with timeout?
-    TcpListener::bind(full_address).expect("Failed to create server - address invalid")
+    TcpListener::bind(&addresses[..]).expect("Failed to create server - address invalid")
 }
 
-fn create_ipv4_socket(address: &str, port: u16) -> SocketAddr {
-    let octets = ipv4_address_to_byte_vec(address);
-    let ipv4 = Ipv4Addr::from(octets);
-    SocketAddr::new(IpAddr::V4(ipv4), port)
+// A socket file left behind by a host that didn't exit cleanly is replaced,
+// one that still has a host behind it is not and neither is anything that isn't a socket
+#[cfg(unix)]
+pub fn create_unix_server(path: &str) -> Box<dyn Listener> {
+    match UnixListener::bind(path) {
+        Ok(listener) => return Box::new(listener),
+        Err(err) if err.kind() != ErrorKind::AddrInUse => panic!("Failed to create server at {path}: {err}"),
+        Err(_) => {}
+    }
+    if UnixStream::connect(path).is_ok() {
+        panic!("Failed to create server - {path} is already in use");
+    }
+    if !std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        panic!("Failed to create server - {path} exists and isn't a socket");
+    }
+    let _ = std::fs::remove_file(path);
+    Box::new(UnixListener::bind(path).expect("Failed to create server - socket path invalid"))
 }
 
-pub fn ipv4_address_to_byte_vec(address: &str) -> [u8; 4] {
-    let mut octets = [0u8; 4];
-    let components = address.split('.');
-    for (i, comp) in components.enumerate() {
-        let Ok(byte) = comp.parse::<u8>() else {
-            panic!("Error: The address {address} is invalid")
-        };
-        octets[i] = byte;
-    }
-    octets
-}
\ No newline at end of file
+#[cfg(not(unix))]
+pub fn create_unix_server(_path: &str) -> Box<dyn Listener> {
+    panic!("Unix sockets aren't supported on this platform")
+}
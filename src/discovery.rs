@@ -0,0 +1,155 @@
+use std::io::{ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Hosts answer probes sent to a multicast group and to the broadcast address,
+// whichever of the two the local network lets through
+pub const DISCOVERY_PORT: u16 = 42_424;
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 99);
+const PROBE: &[u8] = b"fileserver?";
+const ANSWER: &[u8] = b"fileserver!";
+pub const DISCOVERY_WAIT: Duration = Duration::from_secs(1);
+
+// What a host tells about itself:
+// ANSWER | port u16 | flags u8 | parallel streams u8 | nameplate u16 (0 - none) | utf8 name
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Announcement {
+    pub name: String,
+    pub port: u16,
+    pub auto_accept: bool,
+    pub parallel_streams: u8,
+    // set while sharing with a code, the number in front of its words
+    pub nameplate: Option<u16>,
+}
+
+const AUTO_ACCEPT: u8 = 1;
+
+impl Announcement {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = ANSWER.to_vec();
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+        bytes.push(if self.auto_accept { AUTO_ACCEPT } else { 0 });
+        bytes.push(self.parallel_streams);
+        bytes.extend_from_slice(&self.nameplate.unwrap_or(0).to_be_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let fields = bytes.strip_prefix(ANSWER)?;
+        if fields.len() < 6 {
+            return None;
+        }
+        let nameplate = u16::from_be_bytes([fields[4], fields[5]]);
+        Some(Self {
+            port: u16::from_be_bytes([fields[0], fields[1]]),
+            auto_accept: fields[2] & AUTO_ACCEPT != 0,
+            parallel_streams: fields[3],
+            nameplate: (nameplate != 0).then_some(nameplate),
+            name: String::from_utf8_lossy(&fields[6..]).to_string(),
+        })
+    }
+
+    pub fn describe_capabilities(&self) -> String {
+        let mut capabilities = vec![];
+        if let Some(nameplate) = self.nameplate {
+            capabilities.push(format!("sharing with code {nameplate}-.."));
+        }
+        if self.auto_accept {
+            capabilities.push("auto-accept".to_string());
+        }
+        if self.parallel_streams > 0 {
+            capabilities.push(format!("{} parallel streams", self.parallel_streams));
+        }
+        capabilities.join(", ")
+    }
+}
+
+pub struct DiscoveredPeer {
+    // where the answer came from with the port the host listens on
+    pub address: SocketAddr,
+    pub announcement: Announcement,
+}
+
+impl DiscoveredPeer {
+    pub fn describe(&self) -> String {
+        let capabilities = self.announcement.describe_capabilities();
+        match capabilities.is_empty() {
+            true => format!("{} - {}", self.announcement.name, self.address),
+            false => format!("{} - {} ({capabilities})", self.announcement.name, self.address),
+        }
+    }
+}
+
+// Binds before returning so that probes sent right after are answered
+pub fn start_responder(announcement: Announcement, port: u16) -> Result<JoinHandle<()>> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))?;
+    // broadcasts still arrive if the group can't be joined
+    if let Err(err) = socket.join_multicast_v4(&MULTICAST_GROUP, &Ipv4Addr::UNSPECIFIED) {
+        eprintln!("Couldn't join the discovery group: {err}");
+    }
+    let answer = announcement.to_bytes();
+    Ok(thread::spawn(move || {
+        let mut buffer = [0u8; 64];
+        loop {
+            match socket.recv_from(&mut buffer) {
+                Ok((size, sender)) if &buffer[..size] == PROBE => {
+                    let _ = socket.send_to(&answer, sender);
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    eprintln!("Discovery stopped: {err}");
+                    return;
+                }
+            }
+        }
+    }))
+}
+
+// Hosts that answered within wait, each listed once and sorted by name
+pub fn discover(port: u16, wait: Duration) -> Result<Vec<DiscoveredPeer>> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))?;
+    socket.set_broadcast(true)?;
+    socket.set_multicast_ttl_v4(1)?;
+    let multicast = socket.send_to(PROBE, (MULTICAST_GROUP, port));
+    let broadcast = socket.send_to(PROBE, (Ipv4Addr::BROADCAST, port));
+    if let (Err(err), Err(_)) = (multicast, broadcast) {
+        return Err(err);
+    }
+
+    let mut peers: Vec<DiscoveredPeer> = vec![];
+    let deadline = Instant::now() + wait;
+    let mut buffer = [0u8; 512];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(left))?;
+        let (size, sender) = match socket.recv_from(&mut buffer) {
+            Ok(answer) => answer,
+            Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => break,
+            Err(err) => return Err(err),
+        };
+        let Some(announcement) = Announcement::from_bytes(&buffer[..size]) else {
+            continue;
+        };
+        let address = SocketAddr::new(sender.ip(), announcement.port);
+        if !peers.iter().any(|peer| peer.address == address) {
+            peers.push(DiscoveredPeer { address, announcement });
+        }
+    }
+    peers.sort_by(|a, b| a.announcement.name.cmp(&b.announcement.name));
+    Ok(peers)
+}
+
+// Named after the machine unless the config says otherwise
+pub fn default_name() -> String {
+    if let Ok(name) = std::fs::read_to_string("/etc/hostname") {
+        if !name.trim().is_empty() {
+            return name.trim().to_string();
+        }
+    }
+    std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME")).unwrap_or_else(|_| "fileserver".to_string())
+}
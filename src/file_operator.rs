@@ -1,28 +1,37 @@
 use std::fs::File;
-use std::io::{Read, Seek, Result, SeekFrom};
+use std::io::{ErrorKind, Read, Seek, Result, SeekFrom, Stdout, Write};
 
 pub struct FileFeeder {
     file: File,
     length: u64,
     chunk_size: usize,
     buffer: Vec<u8>,
+    // (offset, length) of every hole in the file, in order
+    holes: Vec<(u64, u64)>,
 }
 impl FileFeeder {
     pub fn new(path: &str, chunk_size: usize) -> Result<Self> {
         let buffer = vec![0u8; chunk_size];
         let file_result = File::open(path);
-        let Ok(file) = file_result else {
+        let Ok(mut file) = file_result else {
             return Err(file_result.unwrap_err());
         };
         let length = file.metadata().unwrap().len();
+        let holes = find_holes(&mut file, length);
         Ok(Self {
             file,
             length,
             chunk_size,
             buffer,
+            holes,
         })
     }
+    // Skips over a hole if the cursor is at one, holes aren't chunked
     pub fn has_next_chunk(&mut self) -> bool {
+        let cursor = self.cursor_pos();
+        if let Some((offset, length)) = self.hole_at(cursor) {
+            self.set_cursor_pos(offset + length);
+        }
         self.cursor_pos() < self.length
     }
 
@@ -30,9 +39,14 @@ impl FileFeeder {
     pub fn read_next_chunk(&mut self) -> Result<&[u8]> {
         let cursor = self.cursor_pos();
 
+        // a chunk ends at the next hole so that hole bytes are never read
+        let data_end = match self.next_hole(cursor) {
+            Some((offset, _)) => offset,
+            None => self.length,
+        };
         // or we can quit on EOF error without this logic?
-        if cursor + self.buffer.len() as u64 > self.length {
-            let desired_size = (self.length - cursor) as usize;
+        if cursor + self.buffer.len() as u64 > data_end {
+            let desired_size = (data_end - cursor) as usize;
             let buffer = &mut self.buffer[0..desired_size];
             return match self.file.read_exact(buffer) {
                 Ok(_) => Ok(buffer),
@@ -49,16 +63,195 @@ impl FileFeeder {
         self.length
     }
 
+    // Holes past the cursor, a hole containing the cursor is trimmed to start at it
+    pub fn holes_from(&self, cursor: u64) -> Vec<(u64, u64)> {
+        let mut holes = vec![];
+        for (offset, length) in &self.holes {
+            let end = offset + length;
+            if end <= cursor {
+                continue;
+            }
+            let start = std::cmp::max(*offset, cursor);
+            holes.push((start, end - start));
+        }
+        holes
+    }
+
+    fn hole_at(&self, cursor: u64) -> Option<(u64, u64)> {
+        self.holes.iter()
+            .find(|(offset, length)| *offset <= cursor && cursor < offset + length)
+            .copied()
+    }
+
+    fn next_hole(&self, cursor: u64) -> Option<(u64, u64)> {
+        self.holes.iter().find(|(offset, _)| *offset >= cursor).copied()
+    }
+
     // it doesn't modify anything
     pub fn set_cursor_pos(&mut self, cursor: u64) {
         let offset = SeekFrom::Start(cursor);
         self.file.seek(offset).expect("When does it fail?");
     }
     // it doesn't modify anything
-    fn cursor_pos(&mut self) -> u64 {
+    pub fn cursor_pos(&mut self) -> u64 {
         self.file.stream_position()
             .expect("When does it fail?")
     }
 }
 
+// Feeds chunks out of any reader whose length isn't known, an empty chunk marks the end
+pub struct StreamFeeder<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+impl<R: Read> StreamFeeder<R> {
+    pub fn new(reader: R, chunk_size: usize) -> Self {
+        Self { reader, buffer: vec![0u8; chunk_size], finished: false }
+    }
+    pub fn has_next_chunk(&self) -> bool {
+        !self.finished
+    }
+
+    // Fills the whole buffer unless the reader ends first
+    pub fn read_next_chunk(&mut self) -> Result<&[u8]> {
+        let mut filled = 0;
+        while filled < self.buffer.len() {
+            match self.reader.read(&mut self.buffer[filled..]) {
+                Ok(0) => {
+                    self.finished = true;
+                    break;
+                }
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(&self.buffer[0..filled])
+    }
+}
+
+// A destination for file packets that knows how to skip over a hole
+pub trait HoleWriter: Write {
+    // extends the output by length bytes of zeros ending at end
+    fn write_hole(&mut self, end: u64, length: u64) -> Result<()>;
+}
+
+impl HoleWriter for File {
+    fn write_hole(&mut self, end: u64, _length: u64) -> Result<()> {
+        self.set_len(end)
+    }
+}
+
+impl HoleWriter for Stdout {
+    fn write_hole(&mut self, _end: u64, mut length: u64) -> Result<()> {
+        let zeros = [0u8; 4096];
+        while length > 0 {
+            let size = std::cmp::min(length, zeros.len() as u64) as usize;
+            self.write_all(&zeros[0..size])?;
+            length -= size as u64;
+        }
+        Ok(())
+    }
+}
+
 // TODO struct BufferedFileWriter
+
+// (offset, length) of every chunk a FileFeeder reads from cursor, used to place chunks
+// that arrive out of order since holes make chunks shorter than chunk_size
+pub fn chunk_layout(mut cursor: u64, length: u64, holes: &[(u64, u64)], chunk_size: usize) -> Vec<(u64, u64)> {
+    let mut chunks = vec![];
+    while cursor < length {
+        if let Some((offset, hole_length)) = holes.iter().find(|(offset, len)| *offset <= cursor && cursor < offset + len) {
+            cursor = offset + hole_length;
+            continue;
+        }
+        let data_end = match holes.iter().find(|(offset, _)| *offset >= cursor) {
+            Some((offset, _)) => std::cmp::min(*offset, length),
+            None => length,
+        };
+        let chunk_length = std::cmp::min(chunk_size as u64, data_end - cursor);
+        chunks.push((cursor, chunk_length));
+        cursor += chunk_length;
+    }
+    chunks
+}
+
+// Positional reads and writes don't move the cursor so several threads can share a file
+#[cfg(unix)]
+pub fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buffer, offset)
+}
+
+#[cfg(unix)]
+pub fn write_all_at(file: &File, buffer: &[u8], offset: u64) -> Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+pub fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buffer = &mut buffer[read..];
+                offset += read as u64;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn write_all_at(file: &File, mut buffer: &[u8], mut offset: u64) -> Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        match file.seek_write(buffer, offset) {
+            Ok(written) => {
+                buffer = &buffer[written..];
+                offset += written as u64;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// Walks the file with SEEK_DATA/SEEK_HOLE, leaves the cursor at the start
+#[cfg(target_os = "linux")]
+fn find_holes(file: &mut File, length: u64) -> Vec<(u64, u64)> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let mut holes = vec![];
+    let mut position: u64 = 0;
+    while position < length {
+        let data = unsafe { libc::lseek(fd, position as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            // ENXIO - there's no data past position, the rest is a hole
+            if std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+                holes.push((position, length - position));
+            }
+            break;
+        }
+        let data = data as u64;
+        if data > position {
+            holes.push((position, data - position));
+        }
+        let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            break;
+        }
+        position = hole as u64;
+    }
+    let _ = file.seek(SeekFrom::Start(0));
+    holes
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_holes(_file: &mut File, _length: u64) -> Vec<(u64, u64)> {
+    vec![]
+}
@@ -0,0 +1,123 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+
+// Both are looked up next to config.txt
+pub const IDENTITY_NAME: &str = "identity.key";
+pub const KNOWN_PEERS_NAME: &str = "known_peers";
+
+pub type PublicKey = [u8; 32];
+
+// The long-term keypair of an installation, generated on first use
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self { key: SigningKey::generate(&mut OsRng) }
+    }
+
+    pub fn load_or_create(path: &str) -> Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                let Ok(seed) = <[u8; 32]>::try_from(bytes.as_slice()) else {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("{path} doesn't hold a key")));
+                };
+                Ok(Self { key: SigningKey::from_bytes(&seed) })
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let identity = Self::generate();
+                write_private(path, &identity.key.to_bytes())?;
+                eprintln!("Generated a new identity, fingerprint {}", fingerprint(&identity.public_key()));
+                Ok(identity)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.key.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.key.sign(message).to_bytes()
+    }
+}
+
+pub fn verify(public_key: &PublicKey, message: &[u8], signature: &[u8; 64]) -> bool {
+    let Ok(key) = VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+    key.verify(message, &Signature::from_bytes(signature)).is_ok()
+}
+
+// Only the owner can read the private key
+#[cfg(unix)]
+fn write_private(path: &str, bytes: &[u8]) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?.write_all(bytes)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &str, bytes: &[u8]) -> Result<()> {
+    OpenOptions::new().write(true).create_new(true).open(path)?.write_all(bytes)
+}
+
+// Short enough to compare by eye, the first 8 bytes of the key's SHA-256
+pub fn fingerprint(public_key: &PublicKey) -> String {
+    let digest = Sha256::digest(public_key);
+    digest[..8].chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Keys of the peers dialed before by the address they were dialed at,
+// one "address hex_key" pair per line much like ssh's known_hosts
+pub struct KnownPeers {
+    path: String,
+    peers: Vec<(String, PublicKey)>,
+}
+
+impl KnownPeers {
+    // A missing file means nobody is known yet, lines that don't parse are skipped
+    pub fn load(path: &str) -> Self {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        let peers = content.lines()
+            .filter_map(|line| {
+                let (address, key) = line.trim().rsplit_once(' ')?;
+                Some((address.trim().to_string(), decode_key(key)?))
+            })
+            .collect();
+        Self { path: path.to_string(), peers }
+    }
+
+    pub fn get(&self, address: &str) -> Option<&PublicKey> {
+        self.peers.iter().find(|(known, _)| known == address).map(|(_, key)| key)
+    }
+
+    pub fn remember(&mut self, address: &str, key: &PublicKey) -> Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{address} {}", encode_key(key))?;
+        self.peers.push((address.to_string(), *key));
+        Ok(())
+    }
+}
+
+fn encode_key(key: &PublicKey) -> String {
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_key(hex: &str) -> Option<PublicKey> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
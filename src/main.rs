@@ -1,4 +1,4 @@
-use crate::args::{ProgramArgs, CONNECT, HOST};
+use crate::args::{ProgramArgs, CONNECT, DISCOVER, HOST, INTERFACES, RECEIVE, RELAY, SEND, SHARE};
 use crate::config::Config;
 
 mod connection;
@@ -11,6 +11,21 @@ mod tests;
 mod speedtest;
 mod util;
 mod cli;
+mod archive;
+mod selection;
+mod share;
+mod queue;
+mod parallel;
+mod throttle;
+mod session;
+mod multiplex;
+mod transport;
+mod discovery;
+mod identity;
+mod share_code;
+mod relay;
+mod proxy;
+mod access;
 
 fn main() {
     let mut config = Config::read_config();
@@ -26,6 +41,25 @@ fn main() {
     // Listen to connections, y/n, if n listen for another connection,
     let mode = program_args.args[0].to_lowercase();
     let mode = mode.as_str();
+    // also trusts keys of peers seen for the first time, in every mode
+    if let Some(auto_accept) = program_args.host_auto_accept {
+        config.auto_accept = Some(auto_accept);
+    }
+    if let Some(follow_symlinks) = program_args.follow_symlinks {
+        config.follow_symlinks = Some(follow_symlinks);
+    }
+    if let Some(streams) = program_args.parallel_streams {
+        config.parallel_streams = Some(streams);
+    }
+    if let Some(limit) = program_args.rate_limit {
+        config.rate_limit = Some(limit);
+    }
+    if let Some(path) = program_args.unix_socket {
+        config.unix_socket = Some(path);
+    }
+    if let Some(limit) = config.rate_limit {
+        throttle::set_limit(limit);
+    }
     if HOST.starts_with(mode) {
         if let Some(host_ip) = program_args.ip {
             config.host_ip = Some(host_ip);
@@ -33,8 +67,8 @@ fn main() {
         if let Some(port) = program_args.port {
             config.host_port = Some(port);
         }
-        if let Some(auto_accept) = program_args.host_auto_accept {
-            config.auto_accept = Some(auto_accept);
+        if let Some(interface) = program_args.interface {
+            config.host_interface = Some(interface);
         }
         cli::server_impl(config)
 
@@ -45,7 +79,80 @@ fn main() {
         if let Some(port) = program_args.port {
             config.connect_port = Some(port);
         }
+        if let Some(relay) = program_args.relay {
+            config.relay = Some(relay);
+        }
+        if let Some(token) = program_args.token {
+            config.relay_token = Some(token);
+        }
         cli::client_impl(config)
+    } else if SEND.starts_with(mode) {
+        // send [ip] <path>
+        let positional = &program_args.positional;
+        let (ip, shared_path) = match positional.len() {
+            0 | 1 => {
+                ProgramArgs::print_info();
+                return;
+            }
+            2 => (program_args.ip, &positional[1]),
+            _ => (Some(positional[1].clone()), &positional[2]),
+        };
+        if let Some(ip) = ip {
+            config.connect_ip = Some(ip);
+        }
+        if let Some(port) = program_args.port {
+            config.connect_port = Some(port);
+        }
+        cli::send_impl(config, shared_path)
+    } else if SHARE.starts_with(mode) {
+        // share --code <path>
+        let positional = &program_args.positional;
+        if !program_args.share_code || positional.len() != 2 {
+            ProgramArgs::print_info();
+            return;
+        }
+        if let Some(host_ip) = program_args.ip {
+            config.host_ip = Some(host_ip);
+        }
+        if let Some(interface) = program_args.interface {
+            config.host_interface = Some(interface);
+        }
+        // the receiver learns the port through discovery, any free one will do
+        config.host_port = Some(program_args.port.unwrap_or(0));
+        cli::share_code_impl(config, &positional[1])
+    } else if RECEIVE.starts_with(mode) {
+        // receive <code> dials the sharer, -ip and -p skip looking for it
+        if let Some(code) = program_args.positional.get(1) {
+            config.connect_ip = program_args.ip;
+            config.connect_port = program_args.port;
+            cli::receive_code_impl(config, code, program_args.to_stdout);
+            return;
+        }
+        if let Some(host_ip) = program_args.ip {
+            config.host_ip = Some(host_ip);
+        }
+        if let Some(port) = program_args.port {
+            config.host_port = Some(port);
+        }
+        if let Some(interface) = program_args.interface {
+            config.host_interface = Some(interface);
+        }
+        cli::receive_impl(config, program_args.to_stdout)
+    } else if RELAY.starts_with(mode) {
+        if let Some(host_ip) = program_args.ip {
+            config.host_ip = Some(host_ip);
+        }
+        if let Some(port) = program_args.port {
+            config.host_port = Some(port);
+        }
+        if let Some(interface) = program_args.interface {
+            config.host_interface = Some(interface);
+        }
+        cli::relay_impl(config)
+    } else if DISCOVER.starts_with(mode) {
+        cli::discover_impl()
+    } else if INTERFACES.starts_with(mode) {
+        cli::interfaces_impl()
     }
 
 }
@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::time::Instant;
+use crate::{parallel, throttle, util};
+use crate::file_operator::{FileFeeder, HoleWriter};
+use crate::packet::{Control, ErrorPacket, FilePacket, HoleMapPacket, MB_1};
+use crate::parallel::TransferFile;
+
+// Accepted uploads sharing a connection at once, further shares wait in the queue
+pub const MAX_ACTIVE: usize = 4;
+// Bytes every upload may send per round, small chunks of many small files
+// are worth as much as a single chunk of a large file
+const QUANTUM: i64 = MB_1 as i64;
+
+// Each file is sent the same way as a single file upload: its hole map followed by
+// file packets numbered from 0, all under the transaction id of the upload
+struct Upload {
+    transaction_id: u64,
+    name: String,
+    files: Vec<TransferFile>,
+    // index of the file being sent, it's opened once its turn comes
+    current: usize,
+    feeder: Option<FileFeeder>,
+    chunk_id: u64,
+    // negative when the last chunk went over the quantum
+    credit: i64,
+    sent: u64,
+    total: u64,
+    start: Instant,
+    // a file that couldn't be read ends the upload but not the connection
+    failure: Option<Error>,
+}
+
+impl Upload {
+    // Sends the next chunk, false once every file was sent or one of them failed.
+    // Errors are the connection's
+    fn send_chunk(&mut self, control: &dyn Control) -> Result<bool> {
+        loop {
+            let Some(file) = self.files.get(self.current) else {
+                return Ok(false);
+            };
+            let feeder = match &mut self.feeder {
+                Some(feeder) => feeder,
+                None => {
+                    let mut feeder = match FileFeeder::new(file.path.to_str().unwrap(), MB_1) {
+                        Ok(feeder) => feeder,
+                        Err(err) => {
+                            self.failure = Some(err);
+                            return Ok(false);
+                        }
+                    };
+                    feeder.set_cursor_pos(file.cursor);
+                    let hole_map = HoleMapPacket::new(self.transaction_id, feeder.holes_from(file.cursor));
+                    control.send(&hole_map)?;
+                    self.chunk_id = 0;
+                    self.feeder.insert(feeder)
+                }
+            };
+            if !feeder.has_next_chunk() {
+                self.feeder = None;
+                self.current += 1;
+                continue;
+            }
+            let chunk = match feeder.read_next_chunk() {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    self.failure = Some(err);
+                    return Ok(false);
+                }
+            };
+            let packet = FilePacket::new(self.transaction_id, self.chunk_id, chunk);
+            control.send(&packet)?;
+            let length = chunk.len() as u64;
+            self.chunk_id += 1;
+            self.credit -= length as i64;
+            self.sent += length;
+            print_progress(&self.name, self.sent, self.total, self.start);
+            return Ok(true);
+        }
+    }
+}
+
+fn print_progress(name: &str, done: u64, total: u64, start: Instant) {
+    let speed = done as f64 / MB_1 as f64 / start.elapsed().as_secs_f64();
+    let progress = (done as f64 / total as f64) * 100.0;
+    let eta = util::format_eta(done, total, speed);
+    eprintln!("{name} progress={progress:.2}% ({speed:.2}MB/s{}) ETA: {eta}", throttle::cap_suffix());
+}
+
+// Deficit round robin over the accepted uploads of a connection
+pub struct Uploads {
+    active: VecDeque<Upload>,
+}
+
+impl Uploads {
+    pub fn new() -> Self {
+        Self { active: VecDeque::new() }
+    }
+
+    pub fn add(&mut self, transaction_id: u64, name: String, files: Vec<TransferFile>) {
+        let total = files.iter().map(|file| file.size.saturating_sub(file.cursor)).sum();
+        let upload = Upload {
+            transaction_id,
+            name,
+            files,
+            current: 0,
+            feeder: None,
+            chunk_id: 0,
+            credit: 0,
+            sent: 0,
+            total,
+            start: Instant::now(),
+            failure: None,
+        };
+        self.active.push_back(upload);
+    }
+
+    pub fn transaction_ids(&self) -> Vec<u64> {
+        self.active.iter().map(|upload| upload.transaction_id).collect()
+    }
+
+    // Stops an upload the peer gave up on, returns its name
+    pub fn cancel(&mut self, transaction_id: u64) -> Option<String> {
+        let position = self.active.iter().position(|upload| upload.transaction_id == transaction_id)?;
+        self.active.remove(position).map(|upload| upload.name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    // Lets the upload in front send its quantum and moves it to the back, returns its
+    // transaction id once all of its files were sent or once it failed and the peer was told
+    pub fn send_next(&mut self, control: &dyn Control) -> Result<Option<u64>> {
+        let Some(mut upload) = self.active.pop_front() else {
+            return Ok(None);
+        };
+        upload.credit += QUANTUM;
+        while upload.credit > 0 {
+            if !upload.send_chunk(control)? {
+                if let Some(err) = upload.failure {
+                    eprintln!("Upload of {} failed: {err}", upload.name);
+                    control.send(&ErrorPacket::from_error(upload.transaction_id, &err))?;
+                    return Ok(Some(upload.transaction_id));
+                }
+                let time_format = util::format_time(upload.start.elapsed().as_secs_f64());
+                println!("Upload of {} completed in {time_format}", upload.name);
+                return Ok(Some(upload.transaction_id));
+            }
+        }
+        self.active.push_back(upload);
+        Ok(None)
+    }
+
+    pub fn send_all(&mut self, control: &dyn Control) -> Result<()> {
+        while !self.is_empty() {
+            self.send_next(control)?;
+        }
+        Ok(())
+    }
+}
+
+// The receiving end of an upload, done is handed back once every file is on disk
+struct Download<T> {
+    name: String,
+    files: Vec<TransferFile>,
+    current: usize,
+    // the current file, opened once its hole map arrives
+    file: Option<File>,
+    holes: VecDeque<(u64, u64)>,
+    cursor: u64,
+    expected_chunk_id: u64,
+    received: u64,
+    total: u64,
+    start: Instant,
+    done: T,
+}
+
+impl<T> Download<T> {
+    fn start_file(&mut self, hole_map: HoleMapPacket) -> Result<()> {
+        let Some(file) = self.files.get(self.current) else {
+            return Err(Error::new(ErrorKind::InvalidData, "Hole map arrived after the last file"));
+        };
+        if self.file.is_some() {
+            return Err(Error::new(ErrorKind::InvalidData, "Hole map arrived before the file was complete"));
+        }
+        self.file = Some(parallel::open_destination(file, file.cursor > 0)?);
+        self.holes = hole_map.holes.into();
+        self.cursor = file.cursor;
+        self.expected_chunk_id = 0;
+        self.skip_holes()
+    }
+
+    fn write_chunk(&mut self, packet: FilePacket) -> Result<()> {
+        let Some(file) = &mut self.file else {
+            return Err(Error::new(ErrorKind::InvalidData, "File packet arrived before its hole map"));
+        };
+        if packet.chunk_id != self.expected_chunk_id {
+            return Err(Error::new(ErrorKind::InvalidData, "Terminating read to avoid file corruption (packet was skipped)"));
+        }
+        file.write_all(packet.file_bytes)?;
+        let length = packet.file_bytes.len() as u64;
+        self.cursor += length;
+        self.received += length;
+        self.expected_chunk_id += 1;
+        print_progress(&self.name, self.received, self.total, self.start);
+        self.skip_holes()
+    }
+
+    // Extends the file over holes at the cursor and moves on once the file is complete
+    fn skip_holes(&mut self) -> Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        while let Some((offset, length)) = self.holes.front().copied() {
+            if offset != self.cursor {
+                break;
+            }
+            self.holes.pop_front();
+            self.cursor += length;
+            file.write_hole(self.cursor, length)?;
+        }
+        if self.cursor >= self.files[self.current].size {
+            let _ = file.flush();
+            self.file = None;
+            self.current += 1;
+            if self.files.len() > 1 {
+                println!("Received {}/{} files of {}", self.current, self.files.len(), self.name);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.current == self.files.len()
+    }
+}
+
+// The receiving end of a stream of unknown size, e.g. stdin or an archive
+struct StreamDownload<T> {
+    name: String,
+    sink: Box<dyn Write + Send>,
+    expected_chunk_id: u64,
+    received: u64,
+    start: Instant,
+    done: T,
+}
+
+impl<T> StreamDownload<T> {
+    // True once the end of stream packet arrived
+    fn write_chunk(&mut self, packet: FilePacket) -> Result<bool> {
+        if packet.chunk_id != self.expected_chunk_id {
+            return Err(Error::new(ErrorKind::InvalidData, "Terminating read to avoid stream corruption (packet was skipped)"));
+        }
+        if packet.is_end_of_stream() {
+            self.sink.flush()?;
+            return Ok(true);
+        }
+        self.sink.write_all(packet.file_bytes)?;
+        self.received += packet.file_bytes.len() as u64;
+        self.expected_chunk_id += 1;
+        let speed = self.received as f64 / MB_1 as f64 / self.start.elapsed().as_secs_f64();
+        eprintln!("{} received={} ({speed:.2}MB/s{})", self.name, util::format_size(self.received), throttle::cap_suffix());
+        Ok(false)
+    }
+}
+
+// Accepted downloads of a connection, packets are routed by their transaction id
+pub struct Downloads<T> {
+    active: HashMap<u64, Download<T>>,
+    streams: HashMap<u64, StreamDownload<T>>,
+    // failed on either side, packets that were already on their way are dropped
+    cancelled: HashSet<u64>,
+}
+
+impl<T> Downloads<T> {
+    pub fn new() -> Self {
+        Self { active: HashMap::new(), streams: HashMap::new(), cancelled: HashSet::new() }
+    }
+
+    pub fn add(&mut self, transaction_id: u64, name: String, files: Vec<TransferFile>, done: T) {
+        let total = files.iter().map(|file| file.size.saturating_sub(file.cursor)).sum();
+        let download = Download {
+            name,
+            files,
+            current: 0,
+            file: None,
+            holes: VecDeque::new(),
+            cursor: 0,
+            expected_chunk_id: 0,
+            received: 0,
+            total,
+            start: Instant::now(),
+            done,
+        };
+        self.active.insert(transaction_id, download);
+    }
+
+    pub fn add_stream(&mut self, transaction_id: u64, name: String, sink: Box<dyn Write + Send>, done: T) {
+        let download = StreamDownload { name, sink, expected_chunk_id: 0, received: 0, start: Instant::now(), done };
+        self.streams.insert(transaction_id, download);
+    }
+
+    pub fn files(&self, transaction_id: u64) -> Option<&[TransferFile]> {
+        self.active.get(&transaction_id).map(|download| download.files.as_slice())
+    }
+
+    // For downloads completed some other way, e.g. over parallel streams
+    pub fn remove(&mut self, transaction_id: u64) -> Option<T> {
+        match self.active.remove(&transaction_id) {
+            Some(download) => Some(download.done),
+            None => self.streams.remove(&transaction_id).map(|download| download.done),
+        }
+    }
+
+    // Stops a download the peer gave up on, returns its name
+    pub fn cancel(&mut self, transaction_id: u64) -> Option<String> {
+        self.cancelled.insert(transaction_id);
+        match self.active.remove(&transaction_id) {
+            Some(download) => Some(download.name),
+            None => self.streams.remove(&transaction_id).map(|download| download.name),
+        }
+    }
+
+    // Everything that wasn't complete, e.g. once the connection is broken
+    pub fn drain(&mut self) -> Vec<T> {
+        let streams = self.streams.drain().map(|(_, download)| download.done);
+        self.active.drain().map(|(_, download)| download.done).chain(streams).collect()
+    }
+
+    // Takes a hole map or a file packet, returns done of its download once it's complete.
+    // A download that can't continue (e.g. the disk is full) is dropped and the peer is told,
+    // errors are left for packets that can't be made sense of
+    pub fn receive(&mut self, id: u32, field_bytes: &[u8], control: &dyn Control) -> Result<Option<T>> {
+        let (transaction_id, applied) = match id {
+            HoleMapPacket::ID => {
+                let hole_map = HoleMapPacket::from_bytes(field_bytes)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, format!("Error at HoleMapPacket::from_bytes - {err}")))?;
+                let transaction_id = hole_map.transaction_id;
+                if self.cancelled.contains(&transaction_id) {
+                    return Ok(None);
+                }
+                let download = self.download(transaction_id)?;
+                (transaction_id, download.start_file(hole_map).map(|_| download.is_complete()))
+            }
+            FilePacket::ID => {
+                let packet = FilePacket::wrap(field_bytes)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, format!("Error at FilePacket::wrap - {err}")))?;
+                let transaction_id = packet.transaction_id;
+                if self.cancelled.contains(&transaction_id) {
+                    return Ok(None);
+                }
+                let applied = match self.streams.get_mut(&transaction_id) {
+                    Some(stream) => stream.write_chunk(packet),
+                    None => {
+                        let download = self.download(transaction_id)?;
+                        download.write_chunk(packet).map(|_| download.is_complete())
+                    }
+                };
+                (transaction_id, applied)
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!("{id} isn't a part of a download"))),
+        };
+        match applied {
+            Ok(false) => Ok(None),
+            Ok(true) => Ok(Some(self.complete(transaction_id))),
+            Err(err) => {
+                let name = self.cancel(transaction_id).unwrap();
+                eprintln!("Download of {name} failed: {err}");
+                control.send(&ErrorPacket::from_error(transaction_id, &err))?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn complete(&mut self, transaction_id: u64) -> T {
+        if let Some(stream) = self.streams.remove(&transaction_id) {
+            let time_format = util::format_time(stream.start.elapsed().as_secs_f64());
+            eprintln!("Stream of {} completed in {time_format}", stream.name);
+            return stream.done;
+        }
+        let download = self.active.remove(&transaction_id).unwrap();
+        let time_format = util::format_time(download.start.elapsed().as_secs_f64());
+        eprintln!("Download of {} completed in {time_format}", download.name);
+        download.done
+    }
+
+    fn download(&mut self, transaction_id: u64) -> Result<&mut Download<T>> {
+        self.active.get_mut(&transaction_id)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Transaction {transaction_id} isn't in progress")))
+    }
+}
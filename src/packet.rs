@@ -10,11 +10,12 @@ PACKET STRUCTURE FORMAT:
     packet max size ~ 4.29 GB
 */
 
-use std::fs;
-use std::io::{ErrorKind, Read, Write};
+use std::io::{Error, ErrorKind, Read, Write};
 use std::net::TcpStream;
+use std::sync::Mutex;
 use std::time::{SystemTime};
-use crate::util;
+use crate::throttle;
+use crate::transport::Transport;
 
 pub const KB_125: usize = 128000;
 pub const KB_512: usize = 524288;
@@ -30,17 +31,18 @@ pub trait Packet {
     fn size(&self) -> u32;
 
     // Every packet must serialize itself
-    fn write(&self, stream: &mut TcpStream) -> Result<(), std::io::Error>;
+    fn write(&self, stream: &mut dyn Write) -> Result<(), std::io::Error>;
 
     // The default header impl, don't override
-    fn write_header(&self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+    fn write_header(&self, stream: &mut dyn Write) -> Result<(), std::io::Error> {
         tcp_write_safe(&self.id().to_be_bytes(), stream)
             .and(tcp_write_safe(&self.size().to_be_bytes(), stream))
     }
 }
 
 
-pub fn tcp_write_safe(mut data: &[u8], stream: &mut TcpStream) -> Result<(), std::io::Error> {
+pub fn tcp_write_safe<W: Write + ?Sized>(mut data: &[u8], stream: &mut W) -> Result<(), std::io::Error> {
+    throttle::UPLOAD.take(data.len());
     loop {
         match stream.write(data) {
             Ok(written) => {
@@ -61,10 +63,15 @@ pub fn tcp_write_safe(mut data: &[u8], stream: &mut TcpStream) -> Result<(), std
     }
 }
 
-pub fn tcp_read_safe(mut buffer: &mut [u8], stream: &mut TcpStream) -> std::io::Result<()> {
+pub fn tcp_read_safe<R: Read + ?Sized>(mut buffer: &mut [u8], stream: &mut R) -> std::io::Result<()> {
+    if buffer.is_empty() {
+        return Ok(());
+    }
     loop {
         match stream.read(buffer) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection was closed by peer")),
             Ok(read) => {
+                throttle::DOWNLOAD.take(read);
                 if read == buffer.len() {
                     return Ok(());
                 }
@@ -103,22 +110,88 @@ pub fn tcp_discard_bytes(stream: &mut TcpStream) -> usize {
     }
 }
 
-pub fn read_id(stream: &mut TcpStream) -> u32 {
+// A closed connection is an error rather than a zeroed id that looks like a packet
+pub fn read_id<R: Read + ?Sized>(stream: &mut R) -> std::io::Result<u32> {
     let mut id_bytes = [0u8; 4];
-    let _ = tcp_read_safe(&mut id_bytes, stream);
-    u32::from_be_bytes(id_bytes)
+    tcp_read_safe(&mut id_bytes, stream)?;
+    Ok(u32::from_be_bytes(id_bytes))
 }
 
-pub fn read_content_size(stream: &mut TcpStream) -> u32 {
+pub fn read_content_size<R: Read + ?Sized>(stream: &mut R) -> std::io::Result<u32> {
     let mut size_bytes = [0u8; 4];
-    let _ = tcp_read_safe(&mut size_bytes, stream);
-    u32::from_be_bytes(size_bytes)
+    tcp_read_safe(&mut size_bytes, stream)?;
+    Ok(u32::from_be_bytes(size_bytes))
 }
 
-pub fn read_into_new_buffer(stream: &mut TcpStream, content_size: u32) -> Vec<u8> {
+pub fn read_into_new_buffer<R: Read + ?Sized>(stream: &mut R, content_size: u32) -> std::io::Result<Vec<u8>> {
     let mut buffer = vec![0u8; content_size as usize];
-    let _ = tcp_read_safe(&mut buffer, stream);
-    return buffer;
+    tcp_read_safe(&mut buffer, stream)?;
+    Ok(buffer)
+}
+
+// A packet as it was read, constructed by whoever it's meant for
+pub struct RawPacket {
+    pub id: u32,
+    pub content: Vec<u8>,
+}
+
+pub fn read_packet<R: Read + ?Sized>(stream: &mut R) -> std::io::Result<RawPacket> {
+    loop {
+        if let Some(packet) = poll_packet(stream)? {
+            return Ok(packet);
+        }
+    }
+}
+
+// None if the read timeout passed before any part of a packet arrived,
+// a packet cut off by the timeout is an error
+pub fn poll_packet<R: Read + ?Sized>(stream: &mut R) -> std::io::Result<Option<RawPacket>> {
+    let mut header = [0u8; 8];
+    let read = loop {
+        match stream.read(&mut header) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection was closed by peer")),
+            Ok(read) => break read,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    };
+    throttle::DOWNLOAD.take(read);
+    tcp_read_safe(&mut header[read..], stream)?;
+    let id = u32::from_be_bytes(header[0..4].try_into().unwrap());
+    let content_size = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let mut content = vec![0u8; content_size as usize];
+    tcp_read_safe(&mut content, stream)?;
+    Ok(Some(RawPacket { id, content }))
+}
+
+// The sending half of a connection along with the answers to our offers.
+// Packets are written whole so that several threads can send over the same connection
+pub trait Control {
+    fn send(&self, packet: &dyn Packet) -> std::io::Result<()>;
+
+    // The next packet the peer sent in answer to transaction_id
+    fn await_reply(&self, transaction_id: u64) -> std::io::Result<RawPacket>;
+
+    // A handle of the underlying transport, for its addresses and timeouts
+    fn transport(&self) -> std::io::Result<Box<dyn Transport>>;
+}
+
+// A connection used by one side at a time, replies are simply the next packet
+impl<T: Transport> Control for Mutex<T> {
+    fn send(&self, packet: &dyn Packet) -> std::io::Result<()> {
+        let mut stream = self.lock().unwrap();
+        packet.write_header(&mut *stream)?;
+        packet.write(&mut *stream)
+    }
+
+    fn await_reply(&self, _transaction_id: u64) -> std::io::Result<RawPacket> {
+        read_packet(&mut *self.lock().unwrap())
+    }
+
+    fn transport(&self) -> std::io::Result<Box<dyn Transport>> {
+        self.lock().unwrap().try_clone_box()
+    }
 }
 
 // PACKET STRUCT IMPLEMENTATIONS
@@ -131,9 +204,18 @@ pub struct FileOfferPacket {
 
 impl FileOfferPacket {
     pub const ID: u32 = 100_000;
+    // file_size of a stream whose length isn't known up front (e.g. stdin)
+    pub const UNKNOWN_SIZE: u64 = u64::MAX;
     pub fn new(transaction_id: u64, file_size: u64, file_name: String) -> Self {
         Self { transaction_id, file_size, file_name }
     }
+    pub fn new_stream(transaction_id: u64, file_name: String) -> Self {
+        Self::new(transaction_id, Self::UNKNOWN_SIZE, file_name)
+    }
+    // Streams end with an end of stream FilePacket instead of at file_size
+    pub fn is_stream(&self) -> bool {
+        self.file_size == Self::UNKNOWN_SIZE
+    }
     pub fn construct(field_bytes: &[u8]) -> Result<Self, String> {
         if field_bytes.len() < 17 {
             return Err(format!("Packet has {} bytes but at least 17 were expected", field_bytes.len()));
@@ -160,7 +242,7 @@ impl Packet for FileOfferPacket {
         (8 + 8 + self.file_name.len()) as u32
     }
 
-    fn write(&self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+    fn write(&self, stream: &mut dyn Write) -> Result<(), std::io::Error> {
         tcp_write_safe(&self.transaction_id.to_be_bytes(), stream)
             .and(tcp_write_safe(&self.file_size.to_be_bytes(), stream))
             .and(tcp_write_safe(self.file_name.as_bytes(), stream))
@@ -180,6 +262,13 @@ impl<'r> FilePacket<'r> {
     pub fn new(transaction_id: u64, chunk_id: u64, content: &'r [u8]) -> Self {
         Self { transaction_id, chunk_id, file_bytes: content }
     }
+    // A packet without content terminates a stream of unknown size
+    pub fn end_of_stream(transaction_id: u64, chunk_id: u64) -> Self {
+        Self::new(transaction_id, chunk_id, &[])
+    }
+    pub fn is_end_of_stream(&self) -> bool {
+        self.file_bytes.is_empty()
+    }
     pub fn wrap(field_bytes: &'r [u8]) -> Result<Self, String> {
         let length = field_bytes.len();
         if length < 16 {
@@ -206,7 +295,7 @@ impl<'r> Packet for FilePacket<'r> {
         (8 + 8 + self.file_bytes.len()) as u32
     }
 
-    fn write(&self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+    fn write(&self, stream: &mut dyn Write) -> Result<(), std::io::Error> {
         tcp_write_safe(&self.transaction_id.to_be_bytes(), stream)
             .and(tcp_write_safe(&self.chunk_id.to_be_bytes(), stream))
             .and(tcp_write_safe(self.file_bytes, stream))
@@ -214,6 +303,53 @@ impl<'r> Packet for FilePacket<'r> {
 }
 
 
+// Writes everything as a stream of file packets, finish() sends the end of stream packet
+pub struct FilePacketWriter<'s> {
+    control: &'s dyn Control,
+    transaction_id: u64,
+    chunk_id: u64,
+    buffer: Vec<u8>,
+}
+
+impl<'s> FilePacketWriter<'s> {
+    pub fn new(control: &'s dyn Control, transaction_id: u64) -> Self {
+        Self { control, transaction_id, chunk_id: 0, buffer: Vec::with_capacity(MB_1) }
+    }
+
+    fn write_chunk(&mut self) -> std::io::Result<()> {
+        let packet = FilePacket::new(self.transaction_id, self.chunk_id, &self.buffer);
+        self.control.send(&packet)?;
+        self.chunk_id += 1;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.write_chunk()?;
+        }
+        let end = FilePacket::end_of_stream(self.transaction_id, self.chunk_id);
+        self.control.send(&end)
+    }
+}
+
+impl<'s> Write for FilePacketWriter<'s> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let free = MB_1 - self.buffer.len();
+        let size = std::cmp::min(free, data.len());
+        self.buffer.extend_from_slice(&data[0..size]);
+        if self.buffer.len() == MB_1 {
+            self.write_chunk()?;
+        }
+        Ok(size)
+    }
+
+    // chunks are only sent once full, an incomplete one would end up as an extra packet
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 // Used for testing purposes
 pub struct SpeedPacket<'r> {
     pub random_bytes: &'r [u8],
@@ -238,7 +374,7 @@ impl<'r> Packet for SpeedPacket<'r> {
         self.random_bytes.len() as u32
     }
 
-    fn write(&self, stream: &mut TcpStream) -> Result<(), std::io::Error>{
+    fn write(&self, stream: &mut dyn Write) -> Result<(), std::io::Error>{
         tcp_write_safe(self.random_bytes, stream)
     }
 }
@@ -266,7 +402,7 @@ impl Packet for SpeedtestInfoPacket{
     fn size(&self) -> u32 {
         8u32
     }
-    fn write(&self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+    fn write(&self, stream: &mut dyn Write) -> Result<(), std::io::Error> {
         tcp_write_safe(&self.start_time.to_be_bytes(), stream)
     }
 }
@@ -302,11 +438,140 @@ impl Packet for PingPacket {
         8u32
     }
 
-    fn write(&self, stream: &mut TcpStream) -> std::io::Result<()>  {
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()>  {
         tcp_write_safe(&self.creation_time.to_be_bytes(), stream)
     }
 }
 
+// Sent before the file packets of every upload, lists the holes (offset, length) past the cursor.
+// Hole bytes are never sent, the receiver extends the file over them instead
+pub struct HoleMapPacket {
+    pub transaction_id: u64,
+    pub holes: Vec<(u64, u64)>,
+}
+
+impl HoleMapPacket {
+    pub const ID: u32 = 600_000;
+    pub fn new(transaction_id: u64, holes: Vec<(u64, u64)>) -> Self {
+        Self { transaction_id, holes }
+    }
+
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        let length = field_bytes.len();
+        if length < 12 {
+            return Err(format!("Packet has {length} bytes but at least 12 were expected"));
+        }
+        let id_bytes: [u8; 8] = field_bytes[0..8].try_into().unwrap();
+        let transaction_id = u64::from_be_bytes(id_bytes);
+
+        let count_bytes: [u8; 4] = field_bytes[8..12].try_into().unwrap();
+        let count = u32::from_be_bytes(count_bytes) as usize;
+        if length != 12 + count * 16 {
+            return Err(format!("Packet has {length} bytes but {} were expected", 12 + count * 16));
+        }
+
+        let mut holes = Vec::with_capacity(count);
+        let mut offset = 12;
+        for _ in 0..count {
+            let start_bytes: [u8; 8] = field_bytes[offset..offset + 8].try_into().unwrap();
+            let length_bytes: [u8; 8] = field_bytes[offset + 8..offset + 16].try_into().unwrap();
+            holes.push((u64::from_be_bytes(start_bytes), u64::from_be_bytes(length_bytes)));
+            offset += 16;
+        }
+        Ok(Self { transaction_id, holes })
+    }
+}
+
+impl Packet for HoleMapPacket {
+    fn id(&self) -> u32 {
+        HoleMapPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        (8 + 4 + self.holes.len() * 16) as u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        let count = self.holes.len() as u32;
+        let mut write_result = tcp_write_safe(&self.transaction_id.to_be_bytes(), stream)
+            .and(tcp_write_safe(&count.to_be_bytes(), stream));
+        for (offset, length) in &self.holes {
+            write_result = write_result
+                .and(tcp_write_safe(&offset.to_be_bytes(), stream))
+                .and(tcp_write_safe(&length.to_be_bytes(), stream));
+        }
+        write_result
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            ArchiveFormat::Tar => 0,
+            ArchiveFormat::TarGz => 1,
+        }
+    }
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ArchiveFormat::Tar),
+            1 => Some(ArchiveFormat::TarGz),
+            _ => None,
+        }
+    }
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+}
+
+// Sent in response to a directory offer instead of BeginUploadPacket,
+// the sender replies with the directory archived into a stream of file packets
+pub struct ArchiveRequestPacket {
+    pub transaction_id: u64,
+    pub format: ArchiveFormat,
+}
+
+impl ArchiveRequestPacket {
+    pub const ID: u32 = 700_000;
+    pub fn new(transaction_id: u64, format: ArchiveFormat) -> Self {
+        Self { transaction_id, format }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        if field_bytes.len() != 9 {
+            return Err(format!("Packet has {} bytes but 9 were expected", field_bytes.len()));
+        }
+        let id_bytes: [u8; 8] = field_bytes[0..8].try_into().unwrap();
+        let transaction_id = u64::from_be_bytes(id_bytes);
+        let Some(format) = ArchiveFormat::from_byte(field_bytes[8]) else {
+            return Err(format!("Unknown archive format {}", field_bytes[8]));
+        };
+        Ok(Self { transaction_id, format })
+    }
+}
+
+impl Packet for ArchiveRequestPacket {
+    fn id(&self) -> u32 {
+        ArchiveRequestPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        9u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&self.transaction_id.to_be_bytes(), stream)
+            .and(tcp_write_safe(&[self.format.to_byte()], stream))
+    }
+}
+
 pub struct BeginUploadPacket {
     pub transaction_id: u64,
     pub files_accepted: u32,
@@ -327,29 +592,24 @@ impl BeginUploadPacket {
         self.files_accepted > 0 && self.file_indexes.len() > 0 && self.cursors.len() > 0
     }
 
-    pub fn accept_all(transaction_id: u64, count: u64) -> Self {
-        let mut file_indexes = Vec::with_capacity(count as usize);
-        let mut cursors = Vec::with_capacity(count as usize);
-        for i in 0..count {
-            file_indexes.push(i as u32);
-            cursors.push(0);
-        }
-        Self { transaction_id, files_accepted: count as u32, file_indexes, cursors }
-    }
-
     pub fn new(transaction_id: u64, file_indexes: Vec<u32>, cursors: Vec<u64>) -> Self {
         if file_indexes.len() != cursors.len() {
             panic!("ERROR: To ensure data integrity file_indexes & cursors must be the same length");
         }
         let files_accepted = file_indexes.len() as u32;
         if files_accepted == 0 {
-            return Self::new_empty();
+            return Self::denied(transaction_id);
         }
         Self { transaction_id, files_accepted, file_indexes, cursors }
     }
 
     pub fn new_empty() -> Self {
-        Self { transaction_id: 0, files_accepted: 0, file_indexes: vec![], cursors: vec![] }
+        Self::denied(0)
+    }
+
+    // Accepts none of the files, the offer is answered under its transaction
+    pub fn denied(transaction_id: u64) -> Self {
+        Self { transaction_id, files_accepted: 0, file_indexes: vec![], cursors: vec![] }
     }
 
     pub fn from_bytes(field_bytes: &[u8]) -> Self {
@@ -392,7 +652,7 @@ impl Packet for BeginUploadPacket {
         (8 + 4 + self.file_indexes.len() * 4 + self.cursors.len() * 8) as u32
     }
 
-    fn write(&self, stream: &mut TcpStream) -> std::io::Result<()> {
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
         let mut write_result = tcp_write_safe(&self.transaction_id.to_be_bytes(), stream)
             .and(tcp_write_safe(&self.files_accepted.to_be_bytes(), stream));
 
@@ -408,17 +668,55 @@ impl Packet for BeginUploadPacket {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    File,
+    // link_target holds the path the link points to, as read by read_link
+    Symlink,
+    // link_target holds the name of the offered file sharing the same inode
+    Hardlink,
+}
+
+impl EntryKind {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            EntryKind::File => 0,
+            EntryKind::Symlink => 1,
+            EntryKind::Hardlink => 2,
+        }
+    }
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => EntryKind::Symlink,
+            2 => EntryKind::Hardlink,
+            _ => EntryKind::File,
+        }
+    }
+}
+
 pub struct FileInfo {
     pub size: u64,
     name_size: u64,
     pub name: String,
+    pub kind: EntryKind,
+    target_size: u64,
+    pub link_target: String,
 }
 impl FileInfo {
     pub fn new(name: String, size: u64) -> Self {
-        Self { size, name_size: name.len() as u64, name, }
+        Self { size, name_size: name.len() as u64, name, kind: EntryKind::File, target_size: 0, link_target: String::new() }
+    }
+    pub fn link(name: String, kind: EntryKind, link_target: String) -> Self {
+        let target_size = link_target.len() as u64;
+        Self { size: 0, name_size: name.len() as u64, name, kind, target_size, link_target }
+    }
+    pub fn is_file(&self) -> bool {
+        self.kind == EntryKind::File
     }
 }
 pub struct DirectoryOfferPacket {
+    // assigned by the sender when the offer is made, a re-offer keeps it
+    pub transaction_id: u64,
     pub total_size: u64,
     pub file_count: u64,
     pub name_size: u64,
@@ -428,41 +726,26 @@ pub struct DirectoryOfferPacket {
 impl DirectoryOfferPacket {
     pub const ID: u32 = 900_000;
 
-    pub fn new(directory_path: &str) -> Self {
-        let dir_name = util::get_path_name(directory_path).to_string();
-        let Ok (entries) = fs::read_dir(directory_path) else {
-            eprintln!("Failed to create DirectoryOfferPacket");
-            return Self::empty();
-        };
-
+    // File names are paths relative to the directory, separated with '/'
+    pub fn new(directory_name: String, files: Vec<FileInfo>) -> Self {
         let mut total_size: u64 = 0;
-        let mut files = vec![];
-        for entry in entries {
-            let path = entry.unwrap().path();
-            if !path.is_file() {
-                continue
-            }
-            let Ok(metadata) = path.metadata() else {
-                eprintln!("Skipping entry, unable to retrieve metadata");
-                continue
-            };
-            let size = metadata.len();
-            total_size += size;
-            let entry_name = path.file_name().unwrap().to_str().unwrap().to_string();
-            let file_info = FileInfo::new(entry_name, size);
-            files.push(file_info);
+        for file in &files {
+            total_size += file.size;
         }
-
         let file_count: u64 = files.len() as u64;
-        let name_size = dir_name.len() as u64;
-        Self { total_size, file_count, name_size, directory_name: dir_name, files }
+        let name_size = directory_name.len() as u64;
+        Self { transaction_id: 0, total_size, file_count, name_size, directory_name, files }
     }
 
     pub fn from_bytes(field_bytes: &[u8]) -> Self {
-        if field_bytes.len() < 8*3 + 1 {
+        if field_bytes.len() < 8*4 + 1 {
             eprintln!("Packet is too small to be deserialized");
             return Self::empty();
         }
+        let transaction_bytes: [u8; 8] = field_bytes[0..8].try_into().unwrap();
+        let transaction_id = u64::from_be_bytes(transaction_bytes);
+        let field_bytes = &field_bytes[8..];
+
         let size_bytes: [u8; 8] = field_bytes[0..8].try_into().unwrap();
         let total_size = u64::from_be_bytes(size_bytes);
 
@@ -485,19 +768,38 @@ impl DirectoryOfferPacket {
             let name_size_bytes: [u8; 8] = files_bytes[8..16].try_into().unwrap();
             let name_size = u64::from_be_bytes(name_size_bytes);
 
-            let packet_end = (16 + name_size) as usize;
-            let name_bytes = files_bytes[16..packet_end].to_vec();
+            let name_end = (16 + name_size) as usize;
+            let name_bytes = files_bytes[16..name_end].to_vec();
             let name = String::from_utf8(name_bytes).expect("Failed to decode file name");
-            let file_info = FileInfo { size, name_size, name };
+
+            let kind = EntryKind::from_byte(files_bytes[name_end]);
+            let target_size_bytes: [u8; 8] = files_bytes[name_end + 1..name_end + 9].try_into().unwrap();
+            let target_size = u64::from_be_bytes(target_size_bytes);
+
+            let packet_end = name_end + 9 + target_size as usize;
+            let target_bytes = files_bytes[name_end + 9..packet_end].to_vec();
+            let link_target = String::from_utf8(target_bytes).expect("Failed to decode link target");
+            let file_info = FileInfo { size, name_size, name, kind, target_size, link_target };
             files.push(file_info);
             files_bytes = &files_bytes[packet_end..]
         }
 
-        Self { total_size, file_count, name_size, directory_name: dir_name, files }
+        Self { transaction_id, total_size, file_count, name_size, directory_name: dir_name, files }
     }
 
     pub fn empty() -> Self {
-        Self { total_size: 0, file_count: 0, name_size: 0, directory_name: "".into(), files: vec![]}
+        Self { transaction_id: 0, total_size: 0, file_count: 0, name_size: 0, directory_name: "".into(), files: vec![]}
+    }
+
+    // Indexes of entries whose bytes have to be transferred
+    pub fn regular_file_indexes(&self) -> Vec<u32> {
+        let mut indexes = vec![];
+        for (i, file) in self.files.iter().enumerate() {
+            if file.is_file() {
+                indexes.push(i as u32);
+            }
+        }
+        indexes
     }
 }
 impl Packet for DirectoryOfferPacket {
@@ -506,16 +808,17 @@ impl Packet for DirectoryOfferPacket {
     }
 
     fn size(&self) -> u32 {
-        let mut size = 24 + self.name_size;
+        let mut size = 32 + self.name_size;
         for file in &self.files {
-            size += 8 * 2;
-            size += file.name_size;
+            size += 8 * 3 + 1;
+            size += file.name_size + file.target_size;
         }
         size as u32
     }
 
-    fn write(&self, stream: &mut TcpStream) -> std::io::Result<()> {
-        let mut write_result = tcp_write_safe(&self.total_size.to_be_bytes(), stream)
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        let mut write_result = tcp_write_safe(&self.transaction_id.to_be_bytes(), stream)
+            .and(tcp_write_safe(&self.total_size.to_be_bytes(), stream))
             .and(tcp_write_safe(&self.file_count.to_be_bytes(), stream))
             .and(tcp_write_safe(&self.name_size.to_be_bytes(), stream))
             .and(tcp_write_safe(self.directory_name.as_bytes(), stream));
@@ -525,7 +828,512 @@ impl Packet for DirectoryOfferPacket {
                 .and(tcp_write_safe(&file.size.to_be_bytes(), stream))
                 .and(tcp_write_safe(&file.name_size.to_be_bytes(), stream))
                 .and(tcp_write_safe(file.name.as_bytes(), stream))
+                .and(tcp_write_safe(&[file.kind.to_byte()], stream))
+                .and(tcp_write_safe(&file.target_size.to_be_bytes(), stream))
+                .and(tcp_write_safe(file.link_target.as_bytes(), stream))
         }
         write_result
     }
-}
\ No newline at end of file
+}
+// Sent by the uploader once an upload is accepted, count extra connections can be made to port.
+// The downloader answers with the same packet carrying the count of connections it opened,
+// unless none were offered. Every extra connection starts with the token and its lane index
+pub struct ParallelStreamsPacket {
+    pub transaction_id: u64,
+    pub port: u16,
+    pub count: u8,
+    pub token: u64,
+}
+
+impl ParallelStreamsPacket {
+    pub const ID: u32 = 1_000_000;
+    pub fn new(transaction_id: u64, port: u16, count: u8, token: u64) -> Self {
+        Self { transaction_id, port, count, token }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        if field_bytes.len() != 19 {
+            return Err(format!("Packet has {} bytes but 19 were expected", field_bytes.len()));
+        }
+        let id_bytes: [u8; 8] = field_bytes[0..8].try_into().unwrap();
+        let transaction_id = u64::from_be_bytes(id_bytes);
+        let port_bytes: [u8; 2] = field_bytes[8..10].try_into().unwrap();
+        let port = u16::from_be_bytes(port_bytes);
+        let count = field_bytes[10];
+        let token_bytes: [u8; 8] = field_bytes[11..19].try_into().unwrap();
+        let token = u64::from_be_bytes(token_bytes);
+        Ok(Self { transaction_id, port, count, token })
+    }
+}
+
+impl Packet for ParallelStreamsPacket {
+    fn id(&self) -> u32 {
+        ParallelStreamsPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        19u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&self.transaction_id.to_be_bytes(), stream)
+            .and(tcp_write_safe(&self.port.to_be_bytes(), stream))
+            .and(tcp_write_safe(&[self.count], stream))
+            .and(tcp_write_safe(&self.token.to_be_bytes(), stream))
+    }
+}
+
+// The first packet of every connection, the connecting side introduces its session and the
+// accepting side answers with the same id. A session id that was seen before is a reconnect
+pub struct HelloPacket {
+    pub session_id: u64,
+}
+
+impl HelloPacket {
+    pub const ID: u32 = 1_100_000;
+    pub fn new(session_id: u64) -> Self {
+        Self { session_id }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        if field_bytes.len() != 8 {
+            return Err(format!("Packet has {} bytes but 8 were expected", field_bytes.len()));
+        }
+        let id_bytes: [u8; 8] = field_bytes.try_into().unwrap();
+        Ok(Self { session_id: u64::from_be_bytes(id_bytes) })
+    }
+}
+
+impl Packet for HelloPacket {
+    fn id(&self) -> u32 {
+        HelloPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        8u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&self.session_id.to_be_bytes(), stream)
+    }
+}
+
+// Follows the hello exchange, each side presents its long-term public key along with a fresh
+// nonce the other side has to sign, which proves it holds the matching private key
+pub struct IdentityPacket {
+    pub public_key: [u8; 32],
+    pub nonce: [u8; 32],
+}
+
+impl IdentityPacket {
+    pub const ID: u32 = 1_500_000;
+    pub fn new(public_key: [u8; 32], nonce: [u8; 32]) -> Self {
+        Self { public_key, nonce }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        if field_bytes.len() != 64 {
+            return Err(format!("Packet has {} bytes but 64 were expected", field_bytes.len()));
+        }
+        Ok(Self {
+            public_key: field_bytes[..32].try_into().unwrap(),
+            nonce: field_bytes[32..].try_into().unwrap(),
+        })
+    }
+}
+
+impl Packet for IdentityPacket {
+    fn id(&self) -> u32 {
+        IdentityPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        64u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&self.public_key, stream)
+            .and(tcp_write_safe(&self.nonce, stream))
+    }
+}
+
+// The signature over both identity packets, see session::exchange_identities
+pub struct IdentityProofPacket {
+    pub signature: [u8; 64],
+}
+
+impl IdentityProofPacket {
+    pub const ID: u32 = 1_600_000;
+    pub fn new(signature: [u8; 64]) -> Self {
+        Self { signature }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        let Ok(signature) = field_bytes.try_into() else {
+            return Err(format!("Packet has {} bytes but 64 were expected", field_bytes.len()));
+        };
+        Ok(Self { signature })
+    }
+}
+
+impl Packet for IdentityProofPacket {
+    fn id(&self) -> u32 {
+        IdentityProofPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        64u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&self.signature, stream)
+    }
+}
+
+// The PAKE message of a share code exchange, see share_code::authenticate
+pub struct CodeExchangePacket {
+    pub message: Vec<u8>,
+}
+
+impl CodeExchangePacket {
+    pub const ID: u32 = 1_700_000;
+    pub fn new(message: Vec<u8>) -> Self {
+        Self { message }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Self {
+        Self { message: field_bytes.to_vec() }
+    }
+}
+
+impl Packet for CodeExchangePacket {
+    fn id(&self) -> u32 {
+        CodeExchangePacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        self.message.len() as u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&self.message, stream)
+    }
+}
+
+// Proves the key derived from the code is the same on both sides
+pub struct CodeConfirmPacket {
+    pub mac: [u8; 32],
+}
+
+impl CodeConfirmPacket {
+    pub const ID: u32 = 1_800_000;
+    pub fn new(mac: [u8; 32]) -> Self {
+        Self { mac }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        let Ok(mac) = field_bytes.try_into() else {
+            return Err(format!("Packet has {} bytes but 32 were expected", field_bytes.len()));
+        };
+        Ok(Self { mac })
+    }
+}
+
+impl Packet for CodeConfirmPacket {
+    fn id(&self) -> u32 {
+        CodeConfirmPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        32u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&self.mac, stream)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RelayRole {
+    // joining for the first time, the relay decides
+    Any,
+    // coming back to a relayed session on the side that answered the greeting
+    Accepting,
+    // coming back to a relayed session on the side that greeted
+    Dialing,
+}
+
+impl RelayRole {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            RelayRole::Any => 0,
+            RelayRole::Accepting => 1,
+            RelayRole::Dialing => 2,
+        }
+    }
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(RelayRole::Any),
+            1 => Some(RelayRole::Accepting),
+            2 => Some(RelayRole::Dialing),
+            _ => None,
+        }
+    }
+}
+
+// The only packet a relay reads, clients presenting the same token are paired
+pub struct RelayJoinPacket {
+    pub role: RelayRole,
+    pub token: String,
+}
+
+impl RelayJoinPacket {
+    pub const ID: u32 = 1_900_000;
+    pub fn new(role: RelayRole, token: &str) -> Self {
+        Self { role, token: token.to_string() }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        let Some((&role, token)) = field_bytes.split_first() else {
+            return Err("Packet is empty".to_string());
+        };
+        let Some(role) = RelayRole::from_byte(role) else {
+            return Err(format!("Unknown relay role {role}"));
+        };
+        let Ok(token) = String::from_utf8(token.to_vec()) else {
+            return Err("Token isn't valid UTF-8".to_string());
+        };
+        Ok(Self { role, token })
+    }
+}
+
+impl Packet for RelayJoinPacket {
+    fn id(&self) -> u32 {
+        RelayJoinPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        1 + self.token.len() as u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&[self.role.to_byte()], stream)
+            .and(tcp_write_safe(self.token.as_bytes(), stream))
+    }
+}
+
+// Sent by the relay to both clients once paired, from then on it only passes bytes along
+pub struct RelayPairedPacket {
+    // whether the receiving client greets, the other one answers
+    pub dialing: bool,
+}
+
+impl RelayPairedPacket {
+    pub const ID: u32 = 2_000_000;
+    pub fn new(dialing: bool) -> Self {
+        Self { dialing }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        if field_bytes.len() != 1 {
+            return Err(format!("Packet has {} bytes but 1 was expected", field_bytes.len()));
+        }
+        Ok(Self { dialing: field_bytes[0] != 0 })
+    }
+}
+
+impl Packet for RelayPairedPacket {
+    fn id(&self) -> u32 {
+        RelayPairedPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        1u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&[self.dialing as u8], stream)
+    }
+}
+
+// Sent periodically over an idle session so that both sides know the other one is still there.
+// It's a ping nobody answers, it carries the time it was made just like PingPacket
+pub struct HeartbeatPacket {
+    pub creation_time: u64,
+}
+
+impl HeartbeatPacket {
+    pub const ID: u32 = 1_200_000;
+    pub fn new() -> Self {
+        Self { creation_time: epoch_time_now() }
+    }
+}
+
+impl Packet for HeartbeatPacket {
+    fn id(&self) -> u32 {
+        HeartbeatPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        8u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&self.creation_time.to_be_bytes(), stream)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GoodbyeReason {
+    // the user typed shutdown
+    Shutdown,
+    // nothing arrived for idle_timeout
+    Unresponsive,
+    // the host declined the connection
+    Rejected,
+}
+
+impl GoodbyeReason {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            GoodbyeReason::Shutdown => 0,
+            GoodbyeReason::Unresponsive => 1,
+            GoodbyeReason::Rejected => 2,
+        }
+    }
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(GoodbyeReason::Shutdown),
+            1 => Some(GoodbyeReason::Unresponsive),
+            2 => Some(GoodbyeReason::Rejected),
+            _ => None,
+        }
+    }
+    pub fn describe(self) -> &'static str {
+        match self {
+            GoodbyeReason::Shutdown => "the peer shut down",
+            GoodbyeReason::Unresponsive => "the peer stopped hearing from us",
+            GoodbyeReason::Rejected => "the connection was rejected",
+        }
+    }
+}
+
+// The last packet of a session, the peer closes the session instead of reconnecting
+pub struct GoodbyePacket {
+    pub reason: GoodbyeReason,
+}
+
+impl GoodbyePacket {
+    pub const ID: u32 = 1_300_000;
+    pub fn new(reason: GoodbyeReason) -> Self {
+        Self { reason }
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        if field_bytes.len() != 1 {
+            return Err(format!("Packet has {} bytes but 1 was expected", field_bytes.len()));
+        }
+        let Some(reason) = GoodbyeReason::from_byte(field_bytes[0]) else {
+            return Err(format!("Unknown goodbye reason {}", field_bytes[0]));
+        };
+        Ok(Self { reason })
+    }
+}
+
+impl Packet for GoodbyePacket {
+    fn id(&self) -> u32 {
+        GoodbyePacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        1u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&[self.reason.to_byte()], stream)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+    Other,
+    InvalidName,
+    NotFound,
+    PermissionDenied,
+    StorageFull,
+    // packets that don't add up, e.g. a skipped chunk
+    InvalidData,
+    Unsupported,
+}
+
+impl ErrorCode {
+    pub fn from_io(err: &Error) -> Self {
+        match err.kind() {
+            ErrorKind::InvalidFilename => ErrorCode::InvalidName,
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => ErrorCode::PermissionDenied,
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge => ErrorCode::StorageFull,
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof => ErrorCode::InvalidData,
+            ErrorKind::Unsupported => ErrorCode::Unsupported,
+            _ => ErrorCode::Other,
+        }
+    }
+    pub fn to_byte(self) -> u8 {
+        match self {
+            ErrorCode::Other => 0,
+            ErrorCode::InvalidName => 1,
+            ErrorCode::NotFound => 2,
+            ErrorCode::PermissionDenied => 3,
+            ErrorCode::StorageFull => 4,
+            ErrorCode::InvalidData => 5,
+            ErrorCode::Unsupported => 6,
+        }
+    }
+    // codes added later are still errors
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => ErrorCode::InvalidName,
+            2 => ErrorCode::NotFound,
+            3 => ErrorCode::PermissionDenied,
+            4 => ErrorCode::StorageFull,
+            5 => ErrorCode::InvalidData,
+            6 => ErrorCode::Unsupported,
+            _ => ErrorCode::Other,
+        }
+    }
+}
+
+// Sent by whichever side gives up on an offer or a transfer, the peer stops its half
+// of the transaction and shows the message
+pub struct ErrorPacket {
+    pub transaction_id: u64,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorPacket {
+    pub const ID: u32 = 1_400_000;
+    pub fn new(transaction_id: u64, code: ErrorCode, message: String) -> Self {
+        Self { transaction_id, code, message }
+    }
+    pub fn from_error(transaction_id: u64, err: &Error) -> Self {
+        Self::new(transaction_id, ErrorCode::from_io(err), err.to_string())
+    }
+    pub fn from_bytes(field_bytes: &[u8]) -> Result<Self, String> {
+        if field_bytes.len() < 9 {
+            return Err(format!("Packet has {} bytes but at least 9 were expected", field_bytes.len()));
+        }
+        let id_bytes: [u8; 8] = field_bytes[0..8].try_into().unwrap();
+        let transaction_id = u64::from_be_bytes(id_bytes);
+        let code = ErrorCode::from_byte(field_bytes[8]);
+        let message = String::from_utf8_lossy(&field_bytes[9..]).into_owned();
+        Ok(Self { transaction_id, code, message })
+    }
+}
+
+impl Packet for ErrorPacket {
+    fn id(&self) -> u32 {
+        ErrorPacket::ID
+    }
+
+    fn size(&self) -> u32 {
+        (8 + 1 + self.message.len()) as u32
+    }
+
+    fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        tcp_write_safe(&self.transaction_id.to_be_bytes(), stream)
+            .and(tcp_write_safe(&[self.code.to_byte()], stream))
+            .and(tcp_write_safe(self.message.as_bytes(), stream))
+    }
+}
@@ -0,0 +1,337 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::{cli, file_operator, packet, throttle, util};
+use crate::file_operator::FileFeeder;
+use crate::packet::{Control, FilePacket, HoleMapPacket, MB_1, Packet, ParallelStreamsPacket, RawPacket};
+use crate::transport::Transport;
+
+// Files with less than this remaining are sent whole over one of the lanes
+// next to other small files, larger files are striped across all lanes
+pub const STRIPE_THRESHOLD: u64 = 8 * MB_1 as u64;
+pub const MAX_STREAMS: u8 = 32;
+const LANE_TIMEOUT: Duration = Duration::from_secs(10);
+// Lanes carry no heartbeats, one that doesn't move for this long is given up
+const STALLED_LANE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A file of an accepted upload, path is local to each side
+#[derive(Clone)]
+pub struct TransferFile {
+    pub path: PathBuf,
+    pub cursor: u64,
+    // the offered size
+    pub size: u64,
+}
+
+impl TransferFile {
+    fn remaining(&self) -> u64 {
+        self.size.saturating_sub(self.cursor)
+    }
+}
+
+// Both ends derive the same plan from the accepted files
+enum Step {
+    Striped(usize),
+    // file k of the batch goes over lane k % lanes
+    Batch(Vec<usize>),
+}
+
+fn plan(files: &[TransferFile]) -> Vec<Step> {
+    let mut steps = vec![];
+    let mut batch = vec![];
+    for (i, file) in files.iter().enumerate() {
+        if file.remaining() < STRIPE_THRESHOLD {
+            batch.push(i);
+            continue;
+        }
+        if !batch.is_empty() {
+            steps.push(Step::Batch(std::mem::take(&mut batch)));
+        }
+        steps.push(Step::Striped(i));
+    }
+    if !batch.is_empty() {
+        steps.push(Step::Batch(batch));
+    }
+    steps
+}
+
+// Uploader side, announces up to count extra connections and accepts the ones the peer opened.
+// Nothing is opened if count is 0 but the announcement is still sent
+pub fn open_lanes(control: &dyn Control, count: u8, transaction_id: u64) -> Result<Vec<TcpStream>> {
+    let count = std::cmp::min(count, MAX_STREAMS);
+    let transport = control.transport()?;
+    // lanes are TCP connections next to the control connection, other transports go without
+    let socket = match transport.tcp() {
+        Some(socket) if count > 0 => socket,
+        _ => {
+            control.send(&ParallelStreamsPacket::new(transaction_id, 0, 0, 0))?;
+            return Ok(vec![]);
+        }
+    };
+    let listener = TcpListener::bind(SocketAddr::new(socket.local_addr()?.ip(), 0))?;
+    let token = rand::random::<u64>();
+    let port = listener.local_addr()?.port();
+    control.send(&ParallelStreamsPacket::new(transaction_id, port, count, token))?;
+
+    let reply = parse_streams_packet(control.await_reply(transaction_id)?)?;
+    if reply.transaction_id != transaction_id {
+        return Err(Error::new(ErrorKind::InvalidData, "Stream announcement was answered for another transaction"));
+    }
+    let opened = std::cmp::min(reply.count, count) as usize;
+    let mut lanes: Vec<Option<TcpStream>> = (0..opened).map(|_| None).collect();
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + LANE_TIMEOUT;
+    while lanes.iter().any(Option::is_none) {
+        // connections that never finish the handshake can't stretch the setup either
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(Error::new(ErrorKind::TimedOut, "Parallel streams weren't connected in time"));
+        }
+        let mut lane = match listener.accept() {
+            Ok((lane, _)) => lane,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        lane.set_nonblocking(false)?;
+        lane.set_read_timeout(Some(left))?;
+        let mut handshake = [0u8; 9];
+        if lane.read_exact(&mut handshake).is_err() {
+            continue;
+        }
+        let lane_token = u64::from_be_bytes(handshake[0..8].try_into().unwrap());
+        let index = handshake[8] as usize;
+        if lane_token != token || index >= opened || lanes[index].is_some() {
+            eprintln!("Rejected a stream connection with an invalid handshake");
+            continue;
+        }
+        copy_timeouts(socket, &lane)?;
+        lanes[index] = Some(lane);
+    }
+    Ok(lanes.into_iter().flatten().collect())
+}
+
+// Downloader side, connects to as many of the announced lanes as it can,
+// wanted is false when the output can't be written at arbitrary offsets
+pub fn join_lanes(control: &mut dyn Transport, wanted: bool) -> Result<Vec<TcpStream>> {
+    let announcement = parse_streams_packet(packet::read_packet(control)?)?;
+    join_announced_lanes(&Mutex::new(control.try_clone_box()?), &announcement, wanted)
+}
+
+// For an announcement that was already read off the control connection
+pub fn join_announced_lanes(control: &dyn Control, announcement: &ParallelStreamsPacket, wanted: bool) -> Result<Vec<TcpStream>> {
+    if announcement.count == 0 {
+        return Ok(vec![]);
+    }
+    let transport = control.transport()?;
+    let mut lanes = vec![];
+    if let Some(socket) = transport.tcp().filter(|_| wanted) {
+        let address = SocketAddr::new(socket.peer_addr()?.ip(), announcement.port);
+        for index in 0..std::cmp::min(announcement.count, MAX_STREAMS) {
+            let mut lane = match TcpStream::connect_timeout(&address, LANE_TIMEOUT) {
+                Ok(lane) => lane,
+                Err(err) => {
+                    eprintln!("Opened {index} out of {} streams: {err}", announcement.count);
+                    break;
+                }
+            };
+            let mut handshake = announcement.token.to_be_bytes().to_vec();
+            handshake.push(index);
+            if packet::tcp_write_safe(&handshake, &mut lane).is_err() {
+                break;
+            }
+            copy_timeouts(socket, &lane)?;
+            lanes.push(lane);
+        }
+    }
+    let reply = ParallelStreamsPacket::new(announcement.transaction_id, announcement.port, lanes.len() as u8, announcement.token);
+    control.send(&reply)?;
+    if !lanes.is_empty() {
+        println!("Downloading over {} streams", lanes.len());
+    }
+    Ok(lanes)
+}
+
+fn parse_streams_packet(packet: RawPacket) -> Result<ParallelStreamsPacket> {
+    if packet.id != ParallelStreamsPacket::ID {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Expected stream announcement, got {}", packet.id)));
+    }
+    ParallelStreamsPacket::from_bytes(&packet.content).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+fn copy_timeouts(control: &TcpStream, lane: &TcpStream) -> Result<()> {
+    lane.set_read_timeout(control.read_timeout()?.or(Some(STALLED_LANE_TIMEOUT)))?;
+    lane.set_write_timeout(control.write_timeout()?.or(Some(STALLED_LANE_TIMEOUT)))
+}
+
+// Runs one job per lane, a failed lane closes the others so that none is left blocked
+fn run_lanes<F>(lanes: &mut [TcpStream], job: F) -> Result<()>
+where F: Fn(usize, &mut TcpStream) -> Result<()> + Sync {
+    let closers = lanes.iter().map(TcpStream::try_clone).collect::<Result<Vec<_>>>()?;
+    let (job, closers) = (&job, &closers);
+    thread::scope(|scope| {
+        let handles: Vec<_> = lanes.iter_mut().enumerate().map(|(index, lane)| {
+            scope.spawn(move || {
+                let result = job(index, lane);
+                if result.is_err() {
+                    for closer in closers {
+                        let _ = closer.shutdown(Shutdown::Both);
+                    }
+                }
+                result
+            })
+        }).collect();
+        handles.into_iter().try_for_each(|handle| handle.join().unwrap())
+    })
+}
+
+struct Progress {
+    total: u64,
+    done: AtomicU64,
+    start: Instant,
+}
+
+impl Progress {
+    fn new(total: u64) -> Self {
+        Self { total, done: AtomicU64::new(0), start: Instant::now() }
+    }
+
+    fn advance(&self, bytes: u64) {
+        let done = self.done.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let speed = done as f64 / MB_1 as f64 / self.start.elapsed().as_secs_f64();
+        let progress = (done as f64 / self.total as f64) * 100.0;
+        let eta = util::format_eta(done, self.total, speed);
+        eprintln!("progress={progress:.2}% ({speed:.2}MB/s{}) ETA: {eta}", throttle::cap_suffix());
+    }
+}
+
+// The control connection isn't used, so it stays free for other transfers and pings
+pub fn upload(lanes: &mut [TcpStream], files: &[TransferFile], transaction_id: u64) -> Result<()> {
+    let start = Instant::now();
+    for step in plan(files) {
+        match step {
+            Step::Striped(i) => upload_striped(lanes, &files[i], transaction_id)?,
+            Step::Batch(batch) => {
+                let lane_count = lanes.len();
+                run_lanes(lanes, |index, lane| {
+                    for file in batch.iter().skip(index).step_by(lane_count) {
+                        let file = &files[*file];
+                        cli::stream_file(file.path.to_str().unwrap(), file.cursor, transaction_id, lane)?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+    }
+    let time_format = util::format_time(start.elapsed().as_secs_f64());
+    println!("Upload over {} streams completed in {time_format}", lanes.len());
+    Ok(())
+}
+
+// The hole map goes over the first lane, chunk n over lane n % lanes
+fn upload_striped(lanes: &mut [TcpStream], file: &TransferFile, transaction_id: u64) -> Result<()> {
+    let path = file.path.to_str().unwrap();
+    let holes = FileFeeder::new(path, MB_1)?.holes_from(file.cursor);
+    let hole_map = HoleMapPacket::new(transaction_id, holes);
+    hole_map.write_header(&mut lanes[0])?;
+    hole_map.write(&mut lanes[0])?;
+    let layout = file_operator::chunk_layout(file.cursor, file.size, &hole_map.holes, MB_1);
+    let progress = Progress::new(file.remaining());
+    let lane_count = lanes.len();
+    run_lanes(lanes, |index, lane| {
+        let source = File::open(path)?;
+        let mut buffer = vec![0u8; MB_1];
+        for chunk_id in (index..layout.len()).step_by(lane_count) {
+            let (offset, length) = layout[chunk_id];
+            let buffer = &mut buffer[0..length as usize];
+            file_operator::read_exact_at(&source, buffer, offset)?;
+            let packet = FilePacket::new(transaction_id, chunk_id as u64, buffer);
+            packet.write_header(lane)?;
+            packet.write(lane)?;
+            progress.advance(length);
+        }
+        Ok(())
+    })
+}
+
+pub fn download(lanes: &mut [TcpStream], files: &[TransferFile], transaction_id: u64) -> Result<()> {
+    let start = Instant::now();
+    for step in plan(files) {
+        match step {
+            Step::Striped(i) => download_striped(lanes, &files[i], transaction_id)?,
+            Step::Batch(batch) => {
+                let lane_count = lanes.len();
+                run_lanes(lanes, |index, lane| {
+                    for file in batch.iter().skip(index).step_by(lane_count) {
+                        let file = &files[*file];
+                        let destination = open_destination(file, file.cursor > 0)?;
+                        cli::read_and_write_file_to_disk(file.cursor, file.size, destination, transaction_id, lane)?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+    }
+    let time_format = util::format_time(start.elapsed().as_secs_f64());
+    eprintln!("Download over {} streams completed in {time_format}", lanes.len());
+    Ok(())
+}
+
+fn download_striped(lanes: &mut [TcpStream], file: &TransferFile, transaction_id: u64) -> Result<()> {
+    let RawPacket { id, content } = packet::read_packet(&mut lanes[0])?;
+    if id != HoleMapPacket::ID {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{id} wasn't expected at this time, hole map was expected")));
+    }
+    let hole_map = HoleMapPacket::from_bytes(&content).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    if hole_map.transaction_id != transaction_id {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Hole map of transaction {} wasn't expected", hole_map.transaction_id)));
+    }
+    let layout = file_operator::chunk_layout(file.cursor, file.size, &hole_map.holes, MB_1);
+    // appending would ignore the offsets
+    let destination = open_destination(file, false)?;
+    let progress = Progress::new(file.remaining());
+    let lane_count = lanes.len();
+    run_lanes(lanes, |index, lane| {
+        let mut buffer = vec![0u8; MB_1];
+        for chunk_id in (index..layout.len()).step_by(lane_count) {
+            let id = packet::read_id(lane)?;
+            if id != FilePacket::ID {
+                return Err(Error::new(ErrorKind::InvalidData, format!("{id} wasn't expected at this time")));
+            }
+            let content_size = packet::read_content_size(lane)? as usize;
+            buffer.resize(content_size, 0);
+            lane.read_exact(&mut buffer)?;
+            throttle::DOWNLOAD.take(content_size);
+            let packet = FilePacket::wrap(&buffer).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            let (offset, length) = layout[chunk_id];
+            if packet.transaction_id != transaction_id || packet.chunk_id != chunk_id as u64 || packet.file_bytes.len() as u64 != length {
+                return Err(Error::new(ErrorKind::InvalidData, "Chunk doesn't match the expected layout"));
+            }
+            file_operator::write_all_at(&destination, packet.file_bytes, offset)?;
+            progress.advance(length);
+        }
+        Ok(())
+    })?;
+    // trailing holes are never written
+    destination.set_len(file.size)
+}
+
+pub fn open_destination(file: &TransferFile, append: bool) -> Result<File> {
+    if let Some(parent) = file.path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if append {
+        OpenOptions::new().append(true).open(&file.path)
+    } else if file.cursor > 0 {
+        OpenOptions::new().write(true).open(&file.path)
+    } else {
+        File::create(&file.path)
+    }
+}
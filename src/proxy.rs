@@ -0,0 +1,219 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpStream};
+use std::time::Duration;
+use crate::connection;
+
+const SOCKS5_PORT: u16 = 1080;
+const HTTP_PORT: u16 = 8080;
+// Covers the whole exchange with the proxy, including it dialing the peer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+// Longer responses aren't from a proxy we can talk to
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const CONNECT_COMMAND: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+// Where outgoing connections are tunneled through, parsed from
+// socks5://[user:password@]host[:port] or http://[user:password@]host[:port]
+#[derive(PartialEq, Eq, Debug)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub address: String,
+    pub credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("The proxy {url} is invalid, expected socks5://host:port or http://host:port"));
+        let (scheme, rest) = url.trim().split_once("://").ok_or_else(invalid)?;
+        let (kind, default_port) = match scheme.to_lowercase().as_str() {
+            // names are always resolved by the proxy
+            "socks5" | "socks5h" => (ProxyKind::Socks5, SOCKS5_PORT),
+            "http" => (ProxyKind::Http, HTTP_PORT),
+            _ => return Err(invalid()),
+        };
+        let rest = rest.trim_end_matches('/');
+        // the password may hold an @ itself
+        let (credentials, host) = match rest.rsplit_once('@') {
+            Some((user_info, host)) => {
+                let (user, password) = user_info.split_once(':').unwrap_or((user_info, ""));
+                (Some((user.to_string(), password.to_string())), host)
+            }
+            None => (None, rest),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let (host, port) = connection::split_port(host, Some(default_port))?;
+        Ok(Self { kind, address: connection::join_host_port(host, port), credentials })
+    }
+
+    // A connection to target (host[:port]) that goes through the proxy
+    pub fn connect(&self, target: &str, default_port: Option<u16>) -> Result<TcpStream> {
+        let (host, port) = connection::split_port(target, default_port)?;
+        let mut stream = connection::connect_tcp(&self.address, None)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let tunneled = match self.kind {
+            ProxyKind::Socks5 => self.socks5_connect(&mut stream, host, port),
+            ProxyKind::Http => self.http_connect(&mut stream, host, port),
+        };
+        tunneled.map_err(|err| Error::new(err.kind(), format!("Proxy {} - {err}", self.address)))?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    // RFC 1928, with username and password authentication from RFC 1929
+    fn socks5_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        let methods: &[u8] = match self.credentials {
+            Some(_) => &[NO_AUTHENTICATION, USERNAME_PASSWORD],
+            None => &[NO_AUTHENTICATION],
+        };
+        let mut greeting = vec![SOCKS_VERSION, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting)?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice)?;
+        if choice[0] != SOCKS_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a SOCKS5 proxy"));
+        }
+        match (choice[1], &self.credentials) {
+            (NO_AUTHENTICATION, _) => {}
+            (USERNAME_PASSWORD, Some((user, password))) => socks5_authenticate(stream, user, password)?,
+            (NO_ACCEPTABLE_METHOD, None) => return Err(Error::new(ErrorKind::PermissionDenied, "The proxy wants credentials")),
+            _ => return Err(Error::new(ErrorKind::PermissionDenied, "The proxy accepts none of our authentication methods")),
+        }
+
+        let mut request = vec![SOCKS_VERSION, CONNECT_COMMAND, 0];
+        if let Ok(ip) = host.parse::<Ipv4Addr>() {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        } else if let Ok(ip) = host.parse::<Ipv6Addr>() {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        } else {
+            if host.len() > 255 {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("{host} is too long for a SOCKS5 proxy")));
+            }
+            request.push(DOMAIN_NAME);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        // VER | REP | RSV | ATYP | BND.ADDR | BND.PORT, the bound address isn't needed
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(socks5_error(reply[1]));
+        }
+        let address_length = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => {
+                let mut length = [0u8; 1];
+                stream.read_exact(&mut length)?;
+                length[0] as usize
+            }
+            atyp => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown address type {atyp} in the proxy's reply"))),
+        };
+        let mut bound = vec![0u8; address_length + 2];
+        stream.read_exact(&mut bound)
+    }
+
+    fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        let target = connection::join_host_port(host, port);
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((user, password)) = &self.credentials {
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", base64(format!("{user}:{password}").as_bytes())));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        let head = read_response_head(stream)?;
+        let status_line = head.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+        match status {
+            Some(200..=299) => Ok(()),
+            Some(407) if self.credentials.is_some() => Err(Error::new(ErrorKind::PermissionDenied, "The proxy rejected the credentials")),
+            Some(407) => Err(Error::new(ErrorKind::PermissionDenied, "The proxy wants credentials")),
+            Some(_) => Err(Error::new(ErrorKind::ConnectionRefused, format!("The proxy answered {status_line}"))),
+            None => Err(Error::new(ErrorKind::InvalidData, "Not an HTTP proxy")),
+        }
+    }
+}
+
+fn socks5_authenticate(stream: &mut TcpStream, user: &str, password: &str) -> Result<()> {
+    if user.len() > 255 || password.len() > 255 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Proxy credentials are limited to 255 bytes each"));
+    }
+    let mut request = vec![1, user.len() as u8];
+    request.extend_from_slice(user.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request)?;
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status)?;
+    match status[1] {
+        0 => Ok(()),
+        _ => Err(Error::new(ErrorKind::PermissionDenied, "The proxy rejected the credentials")),
+    }
+}
+
+fn socks5_error(code: u8) -> Error {
+    let (kind, reason) = match code {
+        2 => (ErrorKind::PermissionDenied, "the connection isn't allowed by its rules"),
+        3 => (ErrorKind::ConnectionRefused, "the network is unreachable"),
+        4 => (ErrorKind::ConnectionRefused, "the host is unreachable"),
+        5 => (ErrorKind::ConnectionRefused, "the connection was refused"),
+        6 => (ErrorKind::TimedOut, "the connection timed out"),
+        7 => (ErrorKind::Unsupported, "connecting isn't supported"),
+        8 => (ErrorKind::Unsupported, "the address type isn't supported"),
+        _ => (ErrorKind::Other, "it failed"),
+    };
+    Error::new(kind, format!("Couldn't reach the peer, {reason}"))
+}
+
+// Read byte by byte, whatever follows the empty line already belongs to the peer
+fn read_response_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = vec![];
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(Error::new(ErrorKind::InvalidData, "The proxy's response is too long"));
+        }
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let triple = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(BASE64_ALPHABET[(triple >> (18 - 6 * i) & 0x3F) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
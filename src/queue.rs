@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use crate::share::ShareRequest;
+
+pub struct ShareJob {
+    pub id: u64,
+    pub request: ShareRequest,
+}
+
+struct QueueState {
+    jobs: VecDeque<ShareJob>,
+    next_id: u64,
+    // id and description of every job being uploaded
+    active: Vec<(u64, String)>,
+    // set while there's no connection to upload through
+    paused: bool,
+}
+
+// Shares waiting to be uploaded, the queue outlives connections so that
+// whatever was left (including an interrupted job) is uploaded after reconnecting
+pub struct TransferQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+impl TransferQueue {
+    pub fn new() -> Self {
+        let state = QueueState { jobs: VecDeque::new(), next_id: 1, active: vec![], paused: true };
+        Self { state: Mutex::new(state), changed: Condvar::new() }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn push(&self, request: ShareRequest) -> u64 {
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.jobs.push_back(ShareJob { id, request });
+        self.changed.notify_all();
+        id
+    }
+
+    // Interrupted jobs go back to the front to be offered again first
+    pub fn requeue(&self, job: ShareJob) {
+        let mut state = self.lock();
+        state.active.retain(|(id, _)| *id != job.id);
+        state.jobs.push_front(job);
+        self.changed.notify_all();
+    }
+
+    // Positions are 1-based as printed by print()
+    pub fn remove(&self, position: usize) -> Option<ShareJob> {
+        Self::take(&mut self.lock(), position)
+    }
+
+    // Taken out and put back under the same lock, so the position can't shift in between
+    pub fn move_to_top(&self, position: usize) -> bool {
+        let mut state = self.lock();
+        let Some(job) = Self::take(&mut state, position) else {
+            return false;
+        };
+        state.jobs.push_front(job);
+        true
+    }
+
+    pub fn move_to_bottom(&self, position: usize) -> bool {
+        let mut state = self.lock();
+        let Some(job) = Self::take(&mut state, position) else {
+            return false;
+        };
+        state.jobs.push_back(job);
+        true
+    }
+
+    fn take(state: &mut QueueState, position: usize) -> Option<ShareJob> {
+        if position == 0 {
+            return None;
+        }
+        state.jobs.remove(position - 1)
+    }
+
+    pub fn print(&self) {
+        let state = self.lock();
+        for (id, description) in &state.active {
+            println!("uploading: #{id} {description}");
+        }
+        if state.jobs.is_empty() {
+            println!("Queue is empty");
+        }
+        for (i, job) in state.jobs.iter().enumerate() {
+            println!("{}. #{} {}", i + 1, job.id, job.request.describe());
+        }
+        if state.paused && !state.jobs.is_empty() {
+            println!("Queue is paused until a connection is established");
+        }
+    }
+
+    // Blocks until there's a job to upload, None once the queue is paused
+    pub fn next_job(&self) -> Option<ShareJob> {
+        let mut state = self.lock();
+        loop {
+            if state.paused {
+                return None;
+            }
+            if let Some(job) = Self::start_job(&mut state) {
+                return Some(job);
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    // Doesn't wait, None if the queue is empty or paused
+    pub fn try_next_job(&self) -> Option<ShareJob> {
+        let mut state = self.lock();
+        if state.paused {
+            return None;
+        }
+        Self::start_job(&mut state)
+    }
+
+    fn start_job(state: &mut QueueState) -> Option<ShareJob> {
+        let job = state.jobs.pop_front()?;
+        state.active.push((job.id, job.request.describe()));
+        Some(job)
+    }
+
+    pub fn finish_job(&self, id: u64) {
+        self.lock().active.retain(|(active_id, _)| *active_id != id);
+    }
+
+    pub fn resume(&self) {
+        self.lock().paused = false;
+        self.changed.notify_all();
+    }
+
+    pub fn pause(&self) {
+        self.lock().paused = true;
+        self.changed.notify_all();
+    }
+}
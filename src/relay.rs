@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind, Result};
+use std::net::Shutdown;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::connection;
+use crate::packet::{self, Packet, RelayJoinPacket, RelayPairedPacket, RelayRole};
+use crate::session;
+use crate::transport::{Listener, Transport, Tunneled};
+
+// How long a client waits at the relay for its peer
+pub const RELAY_WAIT: Duration = Duration::from_secs(180);
+// A client that doesn't say which token it's after by then is dropped
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TOKEN_LENGTH: u32 = 256;
+// Clients waiting at once from a single address and in total, any more are turned away
+pub const MAX_WAITING_PER_PEER: usize = 8;
+const MAX_WAITING: usize = 1024;
+
+// A client waiting for the peer with the same token
+struct Waiting {
+    stream: Box<dyn Transport>,
+    role: RelayRole,
+    peer: String,
+    since: Instant,
+}
+
+// Pairs clients by token and pipes bytes between them, nothing past the join packet is read
+pub fn serve(listener: &dyn Listener) {
+    let waiting = Mutex::new(HashMap::new());
+    thread::scope(|scope| loop {
+        match listener.accept_transport() {
+            Ok((stream, peer)) => {
+                let waiting = &waiting;
+                scope.spawn(move || {
+                    if let Err(err) = admit(stream, &peer, waiting) {
+                        eprintln!("Turned away {peer}: {err}");
+                    }
+                });
+            }
+            Err(err) => eprintln!("Failed to accept connection: {err}"),
+        }
+    });
+}
+
+fn admit(mut stream: Box<dyn Transport>, peer: &str, waiting: &Mutex<HashMap<String, Waiting>>) -> Result<()> {
+    stream.set_read_timeout(Some(JOIN_TIMEOUT))?;
+    let join = read_join(&mut stream)?;
+    stream.set_read_timeout(None)?;
+
+    let mut waiting_clients = waiting.lock().unwrap();
+    // clients give up after RELAY_WAIT, their sockets would be held forever
+    waiting_clients.retain(|_, client| {
+        let fresh = client.since.elapsed() < RELAY_WAIT;
+        if !fresh {
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        fresh
+    });
+    let paired = waiting_clients.remove(&join.token).and_then(|first| {
+        match pair_roles(first.role, join.role) {
+            Some(first_dials) => Some((first, first_dials)),
+            None => {
+                // the same side came back, the one waiting must have gone away
+                let _ = first.stream.shutdown(Shutdown::Both);
+                None
+            }
+        }
+    });
+    let Some((mut first, first_dials)) = paired else {
+        let waiting_from_peer = waiting_clients.values().filter(|client| client.peer == peer).count();
+        if waiting_clients.len() >= MAX_WAITING || waiting_from_peer >= MAX_WAITING_PER_PEER {
+            return Err(Error::new(ErrorKind::ConnectionRefused, "Too many clients are waiting already"));
+        }
+        println!("{peer} is waiting for its peer");
+        waiting_clients.insert(join.token, Waiting { stream, role: join.role, peer: peer.to_string(), since: Instant::now() });
+        return Ok(());
+    };
+    if write_paired(&mut first.stream, first_dials).is_err() {
+        println!("{} left before its peer came, {peer} is waiting instead", first.peer);
+        waiting_clients.insert(join.token, Waiting { stream, role: join.role, peer: peer.to_string(), since: Instant::now() });
+        return Ok(());
+    }
+    drop(waiting_clients);
+    write_paired(&mut stream, !first_dials)?;
+    println!("Paired {} with {peer}", first.peer);
+    pipe(first.stream, stream)?;
+    println!("Relay between {} and {peer} closed", first.peer);
+    Ok(())
+}
+
+fn read_join(stream: &mut dyn Transport) -> Result<RelayJoinPacket> {
+    let id = packet::read_id(stream)?;
+    let packet_size = packet::read_content_size(stream)?;
+    if id != RelayJoinPacket::ID || packet_size > 1 + MAX_TOKEN_LENGTH {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a relay client"));
+    }
+    let field_buffer = packet::read_into_new_buffer(stream, packet_size)?;
+    RelayJoinPacket::from_bytes(&field_buffer).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+fn write_paired(stream: &mut dyn Transport, dialing: bool) -> Result<()> {
+    let paired = RelayPairedPacket::new(dialing);
+    paired.write_header(stream)?;
+    paired.write(stream)
+}
+
+// Whether the client that waited dials, None if both want the same side.
+// Fresh clients are told apart by arrival, whoever waited answers the greeting
+pub fn pair_roles(first: RelayRole, second: RelayRole) -> Option<bool> {
+    match (first, second) {
+        (RelayRole::Dialing, RelayRole::Dialing) | (RelayRole::Accepting, RelayRole::Accepting) => None,
+        (RelayRole::Dialing, _) | (_, RelayRole::Accepting) => Some(true),
+        _ => Some(false),
+    }
+}
+
+// Copies both ways until either side closes, then closes the other one too
+fn pipe(first: Box<dyn Transport>, second: Box<dyn Transport>) -> Result<()> {
+    let (mut first_reader, mut second_reader) = (first.try_clone_box()?, second.try_clone_box()?);
+    let (mut first_writer, mut second_writer) = (first, second);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let _ = io::copy(&mut first_reader, &mut second_writer);
+            let _ = second_writer.shutdown(Shutdown::Both);
+            let _ = first_reader.shutdown(Shutdown::Both);
+        });
+        let _ = io::copy(&mut second_reader, &mut first_writer);
+        let _ = first_writer.shutdown(Shutdown::Both);
+        let _ = second_reader.shutdown(Shutdown::Both);
+    });
+    Ok(())
+}
+
+// Dials the relay and waits there for the peer with the same token.
+// True if this side greets the peer, false if it answers the greeting
+pub fn join(relay: &str, token: &str, role: RelayRole, proxy: Option<&str>) -> Result<(Box<dyn Transport>, bool)> {
+    let mut stream = Tunneled(connection::connect_through(relay, None, proxy)?);
+    let join = RelayJoinPacket::new(role, token);
+    join.write_header(&mut stream)?;
+    join.write(&mut stream)?;
+    stream.set_read_timeout(Some(RELAY_WAIT))?;
+    let field_buffer = match session::read_handshake_packet(&mut stream, RelayPairedPacket::ID) {
+        Ok(field_buffer) => field_buffer,
+        Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {
+            return Err(Error::new(ErrorKind::TimedOut, "The peer didn't come to the relay in time"));
+        }
+        Err(err) => return Err(err),
+    };
+    let paired = RelayPairedPacket::from_bytes(&field_buffer).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    stream.set_read_timeout(None)?;
+    Ok((Box::new(stream), paired.dialing))
+}
+
+// The side of a relayed session that answered the greeting waits for its peer at the relay
+pub struct RelayListener {
+    pub relay: String,
+    pub token: String,
+    pub proxy: Option<String>,
+}
+
+impl Listener for RelayListener {
+    fn accept_transport(&self) -> Result<(Box<dyn Transport>, String)> {
+        let (stream, _) = join(&self.relay, &self.token, RelayRole::Accepting, self.proxy.as_deref())?;
+        Ok((stream, format!("a peer through {}", self.relay)))
+    }
+
+    // joining already waits for the peer
+    fn set_nonblocking(&self, _nonblocking: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("relay {}", self.relay)
+    }
+}
@@ -0,0 +1,166 @@
+use crate::packet::DirectoryOfferPacket;
+use crate::util;
+
+const PAGE_SIZE: usize = 20;
+
+// Picks which files of a directory offer are requested, links can't be selected
+pub struct FileSelection<'o> {
+    offer: &'o DirectoryOfferPacket,
+    selected: Vec<bool>,
+}
+
+impl<'o> FileSelection<'o> {
+    pub fn new(offer: &'o DirectoryOfferPacket) -> Self {
+        Self { offer, selected: vec![false; offer.files.len()] }
+    }
+
+    pub fn select_all(&mut self, selected: bool) {
+        for (i, file) in self.offer.files.iter().enumerate() {
+            self.selected[i] = selected && file.is_file();
+        }
+    }
+
+    // Ranges like "0-10,15,20-"
+    pub fn select_ranges(&mut self, ranges: &str, selected: bool) -> Result<usize, String> {
+        let mut in_ranges = vec![false; self.offer.files.len()];
+        for i in parse_index_ranges(ranges, self.offer.files.len())? {
+            in_ranges[i] = true;
+        }
+        Ok(self.set_where(selected, |i| in_ranges[i]))
+    }
+
+    pub fn select_glob(&mut self, pattern: &str, selected: bool) -> usize {
+        let files = &self.offer.files;
+        self.set_where(selected, |i| util::glob_match(pattern, &files[i].name))
+    }
+
+    // Deselects files outside of the size bounds
+    pub fn keep_sizes(&mut self, min: u64, max: u64) -> usize {
+        let files = &self.offer.files;
+        self.set_where(false, |i| files[i].size < min || files[i].size > max)
+    }
+
+    fn set_where<F: Fn(usize) -> bool>(&mut self, selected: bool, predicate: F) -> usize {
+        let mut changed = 0;
+        for (i, file) in self.offer.files.iter().enumerate() {
+            if !file.is_file() || self.selected[i] == selected || !predicate(i) {
+                continue;
+            }
+            self.selected[i] = selected;
+            changed += 1;
+        }
+        changed
+    }
+
+    pub fn indexes(&self) -> Vec<u32> {
+        let mut indexes = vec![];
+        for (i, selected) in self.selected.iter().enumerate() {
+            if *selected {
+                indexes.push(i as u32);
+            }
+        }
+        indexes
+    }
+
+    pub fn selected_size(&self) -> u64 {
+        let mut size = 0;
+        for index in self.indexes() {
+            size += self.offer.files[index as usize].size;
+        }
+        size
+    }
+
+    pub fn pages(&self) -> usize {
+        self.offer.files.len().div_ceil(PAGE_SIZE)
+    }
+
+    pub fn print_page(&self, page: usize) {
+        let start = page * PAGE_SIZE;
+        let end = std::cmp::min(start + PAGE_SIZE, self.offer.files.len());
+        for i in start..end {
+            let file = &self.offer.files[i];
+            let mark = if self.selected[i] { 'x' } else { ' ' };
+            if file.is_file() {
+                println!("[{mark}] {i:>5} {:>10} {}", util::format_size(file.size), file.name);
+            } else {
+                println!("    {i:>5} {:>10} {} -> {}", "link", file.name, file.link_target);
+            }
+        }
+        println!("Page {}/{}", page + 1, std::cmp::max(self.pages(), 1));
+    }
+}
+
+pub fn parse_index_ranges(ranges: &str, count: usize) -> Result<Vec<usize>, String> {
+    let mut indexes = vec![];
+    for range in ranges.split(',') {
+        let range = range.trim();
+        if range.is_empty() {
+            continue;
+        }
+        let parse = |index: &str| index.trim().parse::<usize>().map_err(|_| format!("Invalid index <{index}>"));
+        let (start, end) = match range.split_once('-') {
+            Some((start, "")) => (parse(start)?, count.saturating_sub(1)),
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(range)?, parse(range)?),
+        };
+        if start > end || end >= count {
+            return Err(format!("Range <{range}> is out of bounds, there are {count} files"));
+        }
+        indexes.extend(start..=end);
+    }
+    Ok(indexes)
+}
+
+// Returns the selected indexes or None if the selection was cancelled
+pub fn select_files(offer: &DirectoryOfferPacket) -> Option<Vec<u32>> {
+    let mut selection = FileSelection::new(offer);
+    let mut page = 0;
+    selection.print_page(page);
+    loop {
+        println!("[list [page], next, pick <0-5,8>, drop <ranges>, glob <pattern>, unglob <pattern>, min <size>, max <size>, all, none, done, cancel]");
+        let line = util::read_line();
+        let (command, argument) = match line.split_once(' ') {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line.as_str(), ""),
+        };
+        match command {
+            "list" | "ls" => {
+                if let Ok(number) = argument.parse::<usize>() {
+                    page = number.saturating_sub(1);
+                }
+                selection.print_page(page);
+            }
+            "next" => {
+                if page + 1 < selection.pages() {
+                    page += 1;
+                }
+                selection.print_page(page);
+            }
+            "pick" | "drop" => match selection.select_ranges(argument, command == "pick") {
+                Ok(changed) => println!("Changed {changed} files"),
+                Err(err) => eprintln!("{err}"),
+            },
+            "glob" => println!("Picked {} files", selection.select_glob(argument, true)),
+            "unglob" => println!("Dropped {} files", selection.select_glob(argument, false)),
+            "min" | "max" => {
+                let Some(size) = util::parse_size(argument) else {
+                    eprintln!("Invalid size <{argument}>");
+                    continue;
+                };
+                let dropped = if command == "min" {
+                    selection.keep_sizes(size, u64::MAX)
+                } else {
+                    selection.keep_sizes(0, size)
+                };
+                println!("Dropped {dropped} files");
+            }
+            "all" => selection.select_all(true),
+            "none" => selection.select_all(false),
+            "done" => return Some(selection.indexes()),
+            "cancel" => return None,
+            _ => eprintln!("Unknown command <{command}>"),
+        }
+        let indexes = selection.indexes();
+        println!("Selected {} files [{}]", indexes.len(), util::format_size(selection.selected_size()));
+    }
+}
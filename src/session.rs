@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::net::{IpAddr, Shutdown};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use crate::access::Gatekeeper;
+use crate::config::Config;
+use crate::{connection, relay, throttle};
+use crate::identity::{self, Identity, PublicKey};
+use crate::transport::{Listener, Transport};
+use crate::packet::{self, Control, FilePacket, GoodbyePacket, GoodbyeReason, HelloPacket, IdentityPacket, IdentityProofPacket, Packet, RawPacket, RelayRole};
+
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 8;
+// How long the accepting side waits for its peer to come back
+const RECONNECT_WAIT: Duration = Duration::from_secs(180);
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+// Greeting packets are small, a peer announcing more isn't one of us
+const MAX_HANDSHAKE_PACKET: u32 = 4096;
+// id and size
+const HEADER_SIZE: usize = 8;
+// How often waiting threads check whether the connection broke in the meantime
+const WAIT_POLL: Duration = Duration::from_millis(200);
+
+// What both sides know about each other once greeted
+pub struct Greeting {
+    pub session_id: u64,
+    pub peer_key: PublicKey,
+}
+
+// Introduces the session to the accepting side, who answers with the same id
+pub fn greet(stream: &mut dyn Transport, session_id: u64, identity: &Identity) -> Result<Greeting> {
+    let hello = HelloPacket::new(session_id);
+    hello.write_header(stream)?;
+    hello.write(stream)?;
+    let answer = read_hello(stream)?;
+    if answer.session_id != session_id {
+        return Err(Error::new(ErrorKind::InvalidData, "Peer answered with another session"));
+    }
+    let peer_key = exchange_identities(stream, identity, session_id, true)?;
+    Ok(Greeting { session_id, peer_key })
+}
+
+// The accepting side of greet
+pub fn answer_greeting(stream: &mut dyn Transport, identity: &Identity) -> Result<Greeting> {
+    let hello = read_hello(stream)?;
+    hello.write_header(stream)?;
+    hello.write(stream)?;
+    let peer_key = exchange_identities(stream, identity, hello.session_id, false)?;
+    Ok(Greeting { session_id: hello.session_id, peer_key })
+}
+
+fn read_hello(stream: &mut dyn Transport) -> Result<HelloPacket> {
+    let field_buffer = read_handshake_packet(stream, HelloPacket::ID)?;
+    HelloPacket::from_bytes(&field_buffer).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+// Both sides present their key with a fresh nonce, then sign everything exchanged so far.
+// The signature covers the other side's nonce so it can't be replayed over another connection
+fn exchange_identities(stream: &mut dyn Transport, identity: &Identity, session_id: u64, dialing: bool) -> Result<PublicKey> {
+    let ours = IdentityPacket::new(identity.public_key(), rand::random());
+    ours.write_header(stream)?;
+    ours.write(stream)?;
+    let field_buffer = read_handshake_packet(stream, IdentityPacket::ID)?;
+    let theirs = IdentityPacket::from_bytes(&field_buffer).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+    let proof = IdentityProofPacket::new(identity.sign(&identity_transcript(session_id, dialing, &ours, &theirs)));
+    proof.write_header(stream)?;
+    proof.write(stream)?;
+    let field_buffer = read_handshake_packet(stream, IdentityProofPacket::ID)?;
+    let their_proof = IdentityProofPacket::from_bytes(&field_buffer).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let transcript = identity_transcript(session_id, !dialing, &theirs, &ours);
+    if !identity::verify(&theirs.public_key, &transcript, &their_proof.signature) {
+        return Err(Error::new(ErrorKind::PermissionDenied, "Peer couldn't prove its identity"));
+    }
+    Ok(theirs.public_key)
+}
+
+fn identity_transcript(session_id: u64, signer_dialing: bool, signer: &IdentityPacket, verifier: &IdentityPacket) -> Vec<u8> {
+    let mut transcript = b"fileserver identity".to_vec();
+    transcript.push(signer_dialing as u8);
+    transcript.extend_from_slice(&session_id.to_be_bytes());
+    for packet in [signer, verifier] {
+        transcript.extend_from_slice(&packet.public_key);
+        transcript.extend_from_slice(&packet.nonce);
+    }
+    transcript
+}
+
+// A goodbye instead of the expected packet means the peer turned us down
+pub fn read_handshake_packet(stream: &mut dyn Transport, expected_id: u32) -> Result<Vec<u8>> {
+    let id = packet::read_id(stream)?;
+    let packet_size = packet::read_content_size(stream)?;
+    if packet_size > MAX_HANDSHAKE_PACKET {
+        return Err(Error::new(ErrorKind::InvalidData, format!("A {packet_size} byte packet is too large for a greeting")));
+    }
+    let field_buffer = packet::read_into_new_buffer(stream, packet_size)?;
+    if id == GoodbyePacket::ID {
+        let goodbye = GoodbyePacket::from_bytes(&field_buffer).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        return Err(Error::new(ErrorKind::ConnectionRefused, goodbye.reason.describe()));
+    }
+    if id != expected_id {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Expected {expected_id} during the greeting, got {id}")));
+    }
+    Ok(field_buffer)
+}
+
+// A packet is written as its header followed by its content, a reply shouldn't wait
+// for the acknowledgement of the header
+fn set_nodelay(stream: &dyn Transport) {
+    if let Some(socket) = stream.tcp() {
+        let _ = socket.set_nodelay(true);
+    }
+}
+
+pub fn new_session_id() -> u64 {
+    rand::random::<u64>()
+}
+
+// Both sides number their own transfers, the lowest bit tells which side made the offer
+// so that transfers going in opposite directions never share an id
+pub fn transaction_id(job_id: u64, dialing: bool) -> u64 {
+    job_id << 1 | dialing as u64
+}
+
+// An established connection that outlives drops, whoever notices the drop calls reconnect.
+// The connecting side dials again with backoff, the accepting side waits for the same session.
+// Packets are sent by any thread through the writer half while a single packet router reads
+// the other half and hands replies and exchange packets over to whoever waits for them
+pub struct Session<'a> {
+    pub id: u64,
+    config: &'a Config,
+    identity: &'a Identity,
+    // a reconnecting peer has to present the same key
+    peer_key: PublicKey,
+    // set on the accepting side
+    listener: Option<&'a dyn Listener>,
+    // the host's, strangers knocking while the peer is away get the same treatment
+    gatekeeper: Option<&'a Gatekeeper>,
+    writer: Mutex<Box<dyn Transport>>,
+    // clone of the current stream, closing it interrupts both halves
+    control: Mutex<Box<dyn Transport>>,
+    // bumped on every reconnect so that a drop noticed twice reconnects once
+    generation: AtomicU64,
+    reconnecting: Mutex<()>,
+    closed: AtomicBool,
+    // answers to our offers by their transaction id
+    replies: Mutex<HashMap<u64, RawPacket>>,
+    reply_arrived: Condvar,
+    // pings and speedtest packets wait here for the rtt and speedtest commands
+    exchange_sender: Sender<RawPacket>,
+    exchange_inbox: Mutex<Receiver<RawPacket>>,
+    exchanging: AtomicBool,
+    // when a packet last arrived, heartbeats included
+    last_seen: Mutex<Instant>,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(greeting: Greeting, stream: impl Transport + 'static, config: &'a Config, identity: &'a Identity, listener: Option<&'a dyn Listener>, gatekeeper: Option<&'a Gatekeeper>) -> Self {
+        set_nodelay(&stream);
+        let control = stream.try_clone_box().expect("Failed to clone socket");
+        let (exchange_sender, exchange_inbox) = mpsc::channel();
+        Self {
+            id: greeting.session_id,
+            config,
+            identity,
+            peer_key: greeting.peer_key,
+            listener,
+            gatekeeper,
+            writer: Mutex::new(Box::new(stream)),
+            control: Mutex::new(control),
+            generation: AtomicU64::new(0),
+            reconnecting: Mutex::new(()),
+            closed: AtomicBool::new(false),
+            replies: Mutex::new(HashMap::new()),
+            reply_arrived: Condvar::new(),
+            exchange_sender,
+            exchange_inbox: Mutex::new(exchange_inbox),
+            exchanging: AtomicBool::new(false),
+            last_seen: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    // The reading half of the current stream along with its generation, which is passed
+    // to reconnect once the stream breaks
+    pub fn reader(&self) -> Result<(Box<dyn Transport>, u64)> {
+        let writer = self.writer.lock().unwrap();
+        Ok((writer.try_clone_box()?, self.generation()))
+    }
+
+    // Used by the packet router whenever the peer is heard from
+    pub fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    // Wraps the reader of the packet router, every byte counts as hearing from the peer
+    pub fn touching<'r>(&'r self, reader: &'r mut dyn Transport) -> TouchingReader<'r, 'a> {
+        TouchingReader { session: self, reader }
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_seen.lock().unwrap().elapsed()
+    }
+
+    // A session waiting for its peer isn't expected to hear from it
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnecting.try_lock().is_err()
+    }
+
+    // Whether transaction_id belongs to one of our offers rather than to one of the peer's
+    pub fn is_ours(&self, transaction_id: u64) -> bool {
+        transaction_id == self.transaction_id(transaction_id >> 1)
+    }
+
+    // Used by the packet router
+    pub fn deliver_reply(&self, transaction_id: u64, packet: RawPacket) {
+        self.replies.lock().unwrap().insert(transaction_id, packet);
+        self.reply_arrived.notify_all();
+    }
+
+    pub fn try_reply(&self, transaction_id: u64) -> Option<RawPacket> {
+        self.replies.lock().unwrap().remove(&transaction_id)
+    }
+
+    // Used by the packet router, kept until an rtt or speedtest command reads them
+    pub fn deliver_exchange(&self, packet: RawPacket) {
+        let _ = self.exchange_sender.send(packet);
+    }
+
+    pub fn is_exchanging(&self) -> bool {
+        self.exchanging.load(Ordering::SeqCst)
+    }
+
+    // Waits for any other exchange to finish first
+    pub fn exchange(&self) -> Exchange<'_> {
+        let inbox = self.exchange_inbox.lock().unwrap();
+        self.exchanging.store(true, Ordering::SeqCst);
+        Exchange { session: self, inbox, generation: self.generation() }
+    }
+
+    pub fn transaction_id(&self, job_id: u64) -> u64 {
+        transaction_id(job_id, self.listener.is_none())
+    }
+
+    // Tells the peer not to reconnect, then closes. The session counts as closed before the
+    // goodbye is sent, the peer may close its end before the goodbye returns
+    pub fn goodbye(&self, reason: GoodbyeReason) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.send(&GoodbyePacket::new(reason));
+        self.close();
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.control.lock().unwrap().shutdown(Shutdown::Both);
+        self.reply_arrived.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    // Replaces the stream of broken_generation, false if the peer can't be reached
+    pub fn reconnect(&self, broken_generation: u64) -> bool {
+        let _reconnecting = self.reconnecting.lock().unwrap();
+        if self.generation.load(Ordering::SeqCst) != broken_generation {
+            return !self.is_closed();
+        }
+        if self.is_closed() {
+            return false;
+        }
+        let _ = self.control.lock().unwrap().shutdown(Shutdown::Both);
+        let new_stream = match self.listener {
+            Some(listener) => self.await_peer(listener),
+            None => self.dial(),
+        };
+        let Some(new_stream) = new_stream else {
+            self.closed.store(true, Ordering::SeqCst);
+            self.reply_arrived.notify_all();
+            return false;
+        };
+        self.config.apply_timeouts(&new_stream);
+        set_nodelay(&new_stream);
+        *self.control.lock().unwrap() = new_stream.try_clone_box().expect("Failed to clone socket");
+        *self.writer.lock().unwrap() = new_stream;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.touch();
+        // answers to offers made over the broken stream won't come
+        self.replies.lock().unwrap().clear();
+        self.reply_arrived.notify_all();
+        println!("Reconnected!");
+        true
+    }
+
+    fn dial(&self) -> Option<Box<dyn Transport>> {
+        let address = self.config.relay.clone().or_else(|| connection::peer_address(self.config))?;
+        let attempts = self.config.reconnect_attempts.unwrap_or(DEFAULT_RECONNECT_ATTEMPTS);
+        let mut backoff = FIRST_BACKOFF;
+        for attempt in 1..=attempts {
+            println!("Reconnecting to {address} in {:?} ({attempt}/{attempts})", backoff);
+            sleep(backoff);
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            let stream = match (&self.config.relay, &self.config.relay_token) {
+                (Some(relay), Some(token)) => relay::join(relay, token, RelayRole::Dialing, self.config.proxy.as_deref()).map(|(stream, _)| stream),
+                _ => connection::connect(self.config),
+            };
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("Reconnect failed: {err}");
+                    continue;
+                }
+            };
+            match greet(&mut stream, self.id, self.identity) {
+                Ok(greeting) if greeting.peer_key == self.peer_key => return Some(stream),
+                Ok(_) => eprintln!("Reconnect failed: the peer presented another key"),
+                Err(err) => eprintln!("Reconnect failed: {err}"),
+            }
+        }
+        eprintln!("Giving up on reconnecting");
+        None
+    }
+
+    // Other peers are turned away until the session's peer comes back
+    fn await_peer(&self, listener: &dyn Listener) -> Option<Box<dyn Transport>> {
+        println!("Waiting for the peer to reconnect..");
+        if listener.set_nonblocking(true).is_err() {
+            return None;
+        }
+        let deadline = Instant::now() + RECONNECT_WAIT;
+        let mut reconnected = None;
+        let mut backoff = FIRST_BACKOFF;
+        while Instant::now() < deadline {
+            let (mut stream, peer) = match listener.accept_transport() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    sleep(Duration::from_millis(100));
+                    continue;
+                }
+                // e.g. an unreachable relay fails right away, each attempt would dial it again
+                Err(err) => {
+                    eprintln!("Failed to accept connection: {err}");
+                    sleep(backoff.min(deadline.saturating_duration_since(Instant::now())));
+                    backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                    continue;
+                }
+            };
+            // relayed peers have no address to go by
+            let gatekeeper = self.gatekeeper.zip(peer.parse::<IpAddr>().ok());
+            if let Some(Err(refusal)) = gatekeeper.map(|(gatekeeper, ip)| gatekeeper.check(ip)) {
+                println!("Refused connection from {peer}, {}", refusal.describe());
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
+            }
+            let _ = stream.set_read_timeout(Some(HELLO_TIMEOUT));
+            let failed = match read_hello(&mut stream) {
+                Ok(hello) if hello.session_id == self.id => {
+                    let echoed = hello.write_header(&mut stream).and(hello.write(&mut stream));
+                    let peer_key = echoed.and_then(|_| exchange_identities(&mut stream, self.identity, self.id, false));
+                    match peer_key {
+                        Ok(peer_key) if peer_key == self.peer_key => {
+                            if let Some((gatekeeper, ip)) = gatekeeper {
+                                gatekeeper.record_success(ip);
+                            }
+                            let _ = stream.set_read_timeout(None);
+                            reconnected = Some(stream);
+                            break;
+                        }
+                        Ok(_) => {
+                            eprintln!("A peer presenting another key tried to take over the session");
+                            true
+                        }
+                        Err(_) => true,
+                    }
+                }
+                // a peer of another session isn't at fault
+                Ok(_) => false,
+                Err(_) => true,
+            };
+            let _ = stream.shutdown(Shutdown::Both);
+            if let Some(ban) = gatekeeper.filter(|_| failed).and_then(|(gatekeeper, ip)| gatekeeper.record_failure(ip)) {
+                println!("Banned {peer} for {}s after repeated failed handshakes", ban.as_secs());
+            }
+        }
+        let _ = listener.set_nonblocking(false);
+        if reconnected.is_none() {
+            eprintln!("Peer didn't reconnect in time");
+        }
+        reconnected
+    }
+}
+
+impl Control for Session<'_> {
+    // File chunks are paid for before taking the writer so that a throttled chunk doesn't hold up
+    // pings, heartbeats and goodbyes, which aren't throttled at all
+    fn send(&self, packet: &dyn Packet) -> Result<()> {
+        if packet.id() == FilePacket::ID {
+            throttle::UPLOAD.take(HEADER_SIZE + packet.size() as usize);
+        }
+        let mut writer = self.writer.lock().unwrap();
+        throttle::waived(|| {
+            packet.write_header(&mut *writer)?;
+            packet.write(&mut *writer)
+        })
+    }
+
+    // Fails once the stream the offer was made over breaks
+    fn await_reply(&self, transaction_id: u64) -> Result<RawPacket> {
+        let generation = self.generation();
+        let mut replies = self.replies.lock().unwrap();
+        loop {
+            if let Some(reply) = replies.remove(&transaction_id) {
+                return Ok(reply);
+            }
+            if self.is_closed() || self.generation() != generation {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "Connection was lost while waiting for the peer"));
+            }
+            replies = self.reply_arrived.wait_timeout(replies, WAIT_POLL).unwrap().0;
+        }
+    }
+
+    fn transport(&self) -> Result<Box<dyn Transport>> {
+        self.writer.lock().unwrap().try_clone_box()
+    }
+}
+
+// A 1 MB chunk can take longer than idle_timeout to arrive under a low limit,
+// the peer isn't silent while it does
+pub struct TouchingReader<'r, 'a> {
+    session: &'r Session<'a>,
+    reader: &'r mut dyn Transport,
+}
+
+impl Read for TouchingReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.reader.read(buf)?;
+        if read > 0 {
+            self.session.touch();
+        }
+        Ok(read)
+    }
+}
+
+// Both sides step through an rtt or speedtest run together, the packets the peer sends
+// meanwhile are read one by one
+pub struct Exchange<'s> {
+    session: &'s Session<'s>,
+    inbox: MutexGuard<'s, Receiver<RawPacket>>,
+    generation: u64,
+}
+
+impl Exchange<'_> {
+    pub fn send(&self, packet: &dyn Packet) -> Result<()> {
+        self.session.send(packet)
+    }
+
+    pub fn next(&self) -> Result<RawPacket> {
+        loop {
+            match self.inbox.recv_timeout(WAIT_POLL) {
+                Ok(packet) => return Ok(packet),
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.session.is_closed() || self.session.generation() != self.generation {
+                        return Err(Error::new(ErrorKind::ConnectionAborted, "Connection was lost"));
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::from(ErrorKind::ConnectionAborted)),
+            }
+        }
+    }
+}
+
+impl Drop for Exchange<'_> {
+    fn drop(&mut self) {
+        self.session.exchanging.store(false, Ordering::SeqCst);
+    }
+}
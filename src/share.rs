@@ -0,0 +1,290 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::packet::{DirectoryOfferPacket, EntryKind, FileInfo};
+use crate::util;
+
+// Ignore files are read from every shared directory, their rules apply to its whole subtree
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".fsignore"];
+// Name of the offered directory when more than a single directory is shared
+const SHARED_DIR_NAME: &str = "shared";
+const EXCLUDE: &str = "--exclude";
+
+// share a.txt b/ "*.iso" --exclude "*.tmp"
+pub struct ShareRequest {
+    pub paths: Vec<String>,
+    pub excludes: Vec<String>,
+}
+
+impl ShareRequest {
+    pub fn parse(arguments: &[String]) -> Self {
+        let mut paths = vec![];
+        let mut excludes = vec![];
+        let mut i = 0;
+        while i < arguments.len() {
+            let argument = &arguments[i];
+            if argument == EXCLUDE && i + 1 < arguments.len() {
+                excludes.push(arguments[i + 1].clone());
+                i += 1;
+            } else if let Some(pattern) = argument.strip_prefix("--exclude=") {
+                excludes.push(pattern.to_string());
+            } else {
+                paths.push(argument.clone());
+            }
+            i += 1;
+        }
+        Self { paths, excludes }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut description = self.paths.join(" ");
+        for pattern in &self.excludes {
+            description.push_str(&format!(" {EXCLUDE} {pattern}"));
+        }
+        description
+    }
+
+    pub fn single(path: &str) -> Self {
+        Self { paths: vec![path.to_string()], excludes: vec![] }
+    }
+
+    // A lone file is offered on its own instead of inside a directory
+    pub fn single_file(&self) -> Option<&str> {
+        if self.paths.len() != 1 || util::is_glob(&self.paths[0]) {
+            return None;
+        }
+        let path = self.paths[0].as_str();
+        if Path::new(path).is_file() { Some(path) } else { None }
+    }
+}
+
+// The offer along with the local path of every offered entry
+pub struct ShareSet {
+    pub offer: DirectoryOfferPacket,
+    pub sources: Vec<PathBuf>,
+}
+
+impl ShareSet {
+    // Symlinks are offered as links and files sharing an inode are offered once with the rest
+    // as hardlinks to it, unless follow_symlinks is set in which case everything is copied
+    pub fn collect(request: &ShareRequest, follow_symlinks: bool) -> Self {
+        let mut walker = Walker::new(&request.excludes, follow_symlinks);
+        let mut paths = vec![];
+        for path in &request.paths {
+            paths.extend(expand_glob(path));
+        }
+
+        let directory_name = if paths.len() == 1 && paths[0].is_dir() {
+            // a lone directory is offered as itself, its entries are at the top
+            let dir_name = util::get_path_name(paths[0].to_str().unwrap()).to_string();
+            walker.add_directory(&paths[0], "");
+            dir_name
+        } else {
+            for path in &paths {
+                let name = util::get_path_name(path.to_str().unwrap()).to_string();
+                walker.add_path(path, name);
+            }
+            SHARED_DIR_NAME.to_string()
+        };
+        let offer = DirectoryOfferPacket::new(directory_name, walker.files);
+        Self { offer, sources: walker.sources }
+    }
+}
+
+// Wildcards are only expanded in the last component of a path
+fn expand_glob(path: &str) -> Vec<PathBuf> {
+    if !util::is_glob(path) {
+        if fs::symlink_metadata(path).is_err() {
+            eprintln!("File or directory not found: {path}");
+            return vec![];
+        }
+        return vec![PathBuf::from(path)];
+    }
+    let path = Path::new(path);
+    let pattern = util::get_path_name(path.to_str().unwrap());
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Ok(entries) = fs::read_dir(parent) else {
+        eprintln!("Cannot read directory {}", parent.display());
+        return vec![];
+    };
+    let mut matches = vec![];
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        if util::glob_match(pattern, &name.to_string_lossy()) {
+            matches.push(parent.join(name));
+        }
+    }
+    if matches.is_empty() {
+        eprintln!("Nothing matches {}", path.display());
+    }
+    matches.sort();
+    matches
+}
+
+struct IgnoreRule {
+    // directory of the ignore file relative to the offer, ends with '/' unless it's the top
+    base: String,
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    // patterns with a slash are matched against the path relative to base instead of the name
+    anchored: bool,
+}
+
+impl IgnoreRule {
+    fn parse(line: &str, base: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let pattern = line.trim_start_matches('/').to_string();
+        Some(Self { base: base.to_string(), pattern, negated, dir_only, anchored })
+    }
+
+    fn matches(&self, relative_name: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Some(local) = relative_name.strip_prefix(&self.base) else {
+            return false;
+        };
+        if self.anchored {
+            util::glob_match(&self.pattern, local)
+        } else {
+            util::glob_match(&self.pattern, util::get_path_name(local))
+        }
+    }
+}
+
+struct Walker<'r> {
+    excludes: &'r [String],
+    follow_symlinks: bool,
+    rules: Vec<IgnoreRule>,
+    files: Vec<FileInfo>,
+    sources: Vec<PathBuf>,
+    names: HashSet<String>,
+    inodes: HashMap<(u64, u64), String>,
+    visited_dirs: HashSet<PathBuf>,
+}
+
+impl<'r> Walker<'r> {
+    fn new(excludes: &'r [String], follow_symlinks: bool) -> Self {
+        Self {
+            excludes,
+            follow_symlinks,
+            rules: vec![],
+            files: vec![],
+            sources: vec![],
+            names: HashSet::new(),
+            inodes: HashMap::new(),
+            visited_dirs: HashSet::new(),
+        }
+    }
+
+    fn is_skipped(&self, name: &str, is_dir: bool) -> bool {
+        let entry_name = util::get_path_name(name);
+        for pattern in self.excludes {
+            if util::glob_match(pattern, entry_name) || util::glob_match(pattern, name) {
+                return true;
+            }
+        }
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.matches(name, is_dir) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+
+    fn add_path(&mut self, path: &Path, name: String) {
+        let metadata = if self.follow_symlinks { path.metadata() } else { path.symlink_metadata() };
+        let Ok(metadata) = metadata else {
+            eprintln!("Skipping {name}, unable to retrieve metadata");
+            return;
+        };
+        if self.is_skipped(&name, metadata.is_dir()) {
+            return;
+        }
+        if metadata.is_dir() {
+            self.add_directory(path, &name);
+            return;
+        }
+        if !self.names.insert(name.clone()) {
+            eprintln!("Skipping {}, {name} is already offered", path.display());
+            return;
+        }
+        if metadata.is_symlink() {
+            let Ok(target) = fs::read_link(path) else {
+                eprintln!("Skipping {name}, unable to read link");
+                return;
+            };
+            let target = target.to_string_lossy().to_string();
+            self.push(FileInfo::link(name, EntryKind::Symlink, target), path);
+            return;
+        }
+        if !metadata.is_file() {
+            return;
+        }
+        if let Some(inode) = util::hardlink_inode(&metadata).filter(|_| !self.follow_symlinks) {
+            if let Some(first_name) = self.inodes.get(&inode) {
+                let link = FileInfo::link(name, EntryKind::Hardlink, first_name.clone());
+                self.push(link, path);
+                return;
+            }
+            self.inodes.insert(inode, name.clone());
+        }
+        self.push(FileInfo::new(name, metadata.len()), path);
+    }
+
+    // name is the directory's path relative to the offer, empty for the offered directory itself
+    fn add_directory(&mut self, path: &Path, name: &str) {
+        // followed symlinks could lead back up the tree
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if !self.visited_dirs.insert(canonical) {
+            eprintln!("Skipping {}, directory was already visited", path.display());
+            return;
+        }
+        let Ok(entries) = fs::read_dir(path) else {
+            eprintln!("Cannot read directory {}", path.display());
+            return;
+        };
+        let prefix = if name.is_empty() { String::new() } else { format!("{name}/") };
+        let rules_before = self.rules.len();
+        for ignore_file in IGNORE_FILES {
+            let Ok(content) = fs::read_to_string(path.join(ignore_file)) else {
+                continue;
+            };
+            for line in content.lines() {
+                if let Some(rule) = IgnoreRule::parse(line, &prefix) {
+                    self.rules.push(rule);
+                }
+            }
+        }
+
+        let mut entries: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        entries.sort();
+        for entry in entries {
+            let entry_name = util::get_path_name(entry.to_str().unwrap()).to_string();
+            self.add_path(&entry, format!("{prefix}{entry_name}"));
+        }
+        self.rules.truncate(rules_before);
+    }
+
+    fn push(&mut self, file: FileInfo, source: &Path) {
+        self.files.push(file);
+        self.sources.push(source.to_path_buf());
+    }
+}
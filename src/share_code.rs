@@ -0,0 +1,110 @@
+use std::io::{Error, ErrorKind, Result};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity as SpakeIdentity, Password, Spake2};
+use crate::identity::PublicKey;
+use crate::packet::{CodeConfirmPacket, CodeExchangePacket, Packet};
+use crate::session::{self, Greeting};
+use crate::transport::Transport;
+
+// A code looks like 7-purple-sausage. The number (nameplate) is announced over discovery so that
+// the receiver can find the sharer, the words never leave either machine. Both sides run SPAKE2
+// with the whole code as the password: a wrong guess learns nothing and the sharer gives up after it
+const MAX_NAMEPLATE: u16 = 99;
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adobe", "agent", "album", "alien", "alpha", "amber", "angel",
+    "ankle", "apple", "april", "apron", "arena", "army", "arrow", "atlas", "attic", "aunt",
+    "autumn", "bacon", "badge", "bagel", "baker", "bamboo", "banjo", "barn", "basil", "beach",
+    "beard", "beetle", "bench", "berry", "bicycle", "bison", "blanket", "boat", "book", "bottle",
+    "bread", "brick", "bridge", "broom", "bubble", "bucket", "butter", "cabin", "cactus", "camel",
+    "candle", "canoe", "canyon", "carbon", "carpet", "carrot", "castle", "cedar", "cello", "chalk",
+    "cheese", "cherry", "chess", "cider", "circus", "clock", "cloud", "clover", "cobra", "cocoa",
+    "comet", "copper", "coral", "cotton", "cousin", "crayon", "cricket", "crown", "cupcake",
+    "dagger", "daisy", "dancer", "delta", "desert", "diamond", "dinner", "dolphin", "donkey",
+    "dragon", "dream", "drum", "eagle", "echo", "elbow", "ember", "engine", "falcon", "feather",
+    "fennel", "fiddle", "finch", "flame", "flute", "forest", "fossil", "fox", "galaxy", "garden",
+    "garlic", "ginger", "giraffe", "glacier", "glove", "golden", "grape", "gravel", "guitar",
+    "hammer", "harbor", "hazel", "helmet", "honey", "hornet", "husky", "igloo", "island", "ivory",
+    "jacket", "jaguar", "jasmine", "jelly", "jungle", "kayak", "kettle", "kitten", "koala",
+    "ladder", "lagoon", "lantern", "lava", "lemon", "lily", "lizard", "llama", "lobster", "locket",
+    "lotus", "lunar", "magnet", "mango", "maple", "marble", "meadow", "melon", "meteor", "mint",
+    "mirror", "mitten", "monkey", "moose", "muffin", "napkin", "nectar", "needle", "noodle",
+    "oasis", "ocean", "olive", "onion", "orange", "orbit", "orchid", "otter", "owl", "oyster",
+    "paddle", "panda", "parrot", "pasta", "peach", "peanut", "pebble", "pepper", "piano", "pigeon",
+    "pilot", "pine", "pirate", "pizza", "planet", "plum", "pocket", "pony", "poppy", "potato",
+    "prism", "puffin", "pumpkin", "puppet", "purple", "quartz", "quilt", "rabbit", "radar",
+    "radish", "raven", "ribbon", "river", "robin", "rocket", "ruby", "saddle", "salmon", "sausage",
+    "scarf", "shadow", "shell", "silver", "sketch", "snail", "socket", "spider", "sponge", "squash",
+    "stamp", "stone", "sugar", "summit", "sunset", "swan", "tango", "teapot", "thunder", "tiger",
+    "timber", "toast", "tomato", "topaz", "tractor", "trumpet", "tulip", "turtle", "valley",
+    "velvet", "violet", "volcano", "waffle", "walnut", "walrus", "willow", "window", "winter",
+    "wizard", "yogurt", "zebra", "zipper",
+];
+
+pub fn generate() -> String {
+    let mut rng = rand::thread_rng();
+    let nameplate = rng.gen_range(1..=MAX_NAMEPLATE);
+    format!("{nameplate}-{}-{}", WORDS[rng.gen::<u8>() as usize], WORDS[rng.gen::<u8>() as usize])
+}
+
+// Codes are typed by hand, case and surrounding whitespace don't matter
+fn normalize(code: &str) -> String {
+    code.trim().to_lowercase()
+}
+
+// None for something that isn't a code, a typo is caught before it wastes the only attempt
+pub fn parse_nameplate(code: &str) -> Option<u16> {
+    let code = normalize(code);
+    let mut parts = code.split('-');
+    let nameplate = parts.next()?.parse::<u16>().ok().filter(|nameplate| (1..=MAX_NAMEPLATE).contains(nameplate))?;
+    let words: Vec<&str> = parts.collect();
+    if words.len() != 2 || words.iter().any(|word| !WORDS.contains(word)) {
+        return None;
+    }
+    Some(nameplate)
+}
+
+// Both sides derive a key from the code and prove they ended up with the same one. The proof covers
+// the greeting, so the code vouches for the identity keys exchanged there
+pub fn authenticate(stream: &mut dyn Transport, code: &str, greeting: &Greeting, our_key: &PublicKey, sharing: bool) -> Result<()> {
+    let password = Password::new(normalize(code));
+    let (receiver, sharer) = (SpakeIdentity::new(b"fileserver receiver"), SpakeIdentity::new(b"fileserver sharer"));
+    let (spake, message) = match sharing {
+        true => Spake2::<Ed25519Group>::start_b(&password, &receiver, &sharer),
+        false => Spake2::<Ed25519Group>::start_a(&password, &receiver, &sharer),
+    };
+    let ours = CodeExchangePacket::new(message);
+    ours.write_header(stream)?;
+    ours.write(stream)?;
+    let theirs = CodeExchangePacket::from_bytes(&session::read_handshake_packet(stream, CodeExchangePacket::ID)?);
+    let Ok(key) = spake.finish(&theirs.message) else {
+        return Err(Error::new(ErrorKind::InvalidData, "Peer sent a malformed code exchange"));
+    };
+
+    let (sharer_key, receiver_key) = match sharing {
+        true => (our_key, &greeting.peer_key),
+        false => (&greeting.peer_key, our_key),
+    };
+    let transcript = [&greeting.session_id.to_be_bytes()[..], sharer_key, receiver_key].concat();
+    let (our_side, their_side): (&[u8], &[u8]) = match sharing {
+        true => (b"sharer", b"receiver"),
+        false => (b"receiver", b"sharer"),
+    };
+    let confirm = CodeConfirmPacket::new(confirmation(&key, our_side, &transcript).finalize().into_bytes().into());
+    confirm.write_header(stream)?;
+    confirm.write(stream)?;
+    let field_buffer = session::read_handshake_packet(stream, CodeConfirmPacket::ID)?;
+    let their_confirm = CodeConfirmPacket::from_bytes(&field_buffer).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    if confirmation(&key, their_side, &transcript).verify_slice(&their_confirm.mac).is_err() {
+        return Err(Error::new(ErrorKind::PermissionDenied, "The code didn't match"));
+    }
+    Ok(())
+}
+
+fn confirmation(key: &[u8], side: &[u8], transcript: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(side);
+    mac.update(transcript);
+    mac
+}
@@ -0,0 +1,113 @@
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use crate::util;
+
+// One cap for each direction, shared by every connection and thread of the process
+pub static UPLOAD: RateLimiter = RateLimiter::new();
+pub static DOWNLOAD: RateLimiter = RateLimiter::new();
+
+thread_local! {
+    // set while the thread writes bytes that were paid for up front or go free
+    static WAIVED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Unused bandwidth doesn't accumulate for longer than this
+const BURST: Duration = Duration::from_millis(250);
+
+struct Bucket {
+    // negative when bytes were taken on credit, the taker sleeps it off
+    tokens: f64,
+    refilled: Option<Instant>,
+}
+
+// Token bucket refilled at bytes_per_second, 0 means unlimited
+pub struct RateLimiter {
+    bytes_per_second: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub const fn new() -> Self {
+        let bucket = Bucket { tokens: 0.0, refilled: None };
+        Self { bytes_per_second: AtomicU64::new(0), bucket: Mutex::new(bucket) }
+    }
+
+    pub fn set_limit(&self, bytes_per_second: u64) {
+        self.bytes_per_second.store(bytes_per_second, Ordering::Relaxed);
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens = 0.0;
+        bucket.refilled = None;
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.bytes_per_second.load(Ordering::Relaxed)
+    }
+
+    // Blocks for as long as it takes the bucket to pay for bytes
+    pub fn take(&self, bytes: usize) {
+        let rate = self.limit();
+        if rate == 0 || bytes == 0 || WAIVED.with(Cell::get) {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let burst = rate as f64 * BURST.as_secs_f64();
+            let refill = match bucket.refilled {
+                Some(refilled) => now.duration_since(refilled).as_secs_f64() * rate as f64,
+                None => 0.0,
+            };
+            bucket.tokens = f64::min(bucket.tokens + refill, burst) - bytes as f64;
+            bucket.refilled = Some(now);
+            if bucket.tokens < 0.0 { -bucket.tokens / rate as f64 } else { 0.0 }
+        };
+        if wait > 0.0 {
+            sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+// Nothing taken by the current thread while running f is charged
+pub fn waived<T>(f: impl FnOnce() -> T) -> T {
+    WAIVED.with(|waived| waived.set(true));
+    let result = f();
+    WAIVED.with(|waived| waived.set(false));
+    result
+}
+
+pub fn set_limit(bytes_per_second: u64) {
+    UPLOAD.set_limit(bytes_per_second);
+    DOWNLOAD.set_limit(bytes_per_second);
+}
+
+// Appended to progress lines, empty when there's no cap
+pub fn cap_suffix() -> String {
+    let limit = UPLOAD.limit();
+    if limit == 0 {
+        return String::new();
+    }
+    format!(", cap {}/s", util::format_size(limit))
+}
+
+// limit, limit 20M, limit off
+pub fn handle_limit_command(command: &str) {
+    let argument = command.split_whitespace().nth(1);
+    match argument {
+        None => {}
+        Some("off") | Some("0") => set_limit(0),
+        Some(size) => match util::parse_size(size) {
+            Some(bytes_per_second) => set_limit(bytes_per_second),
+            None => {
+                eprintln!("Invalid rate <{size}>, expected e.g. 20M or off");
+                return;
+            }
+        },
+    }
+    match UPLOAD.limit() {
+        0 => println!("Bandwidth is unlimited"),
+        limit => println!("Bandwidth is limited to {}/s", util::format_size(limit)),
+    }
+}
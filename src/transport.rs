@@ -0,0 +1,186 @@
+use std::io::{Read, Result, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+// A byte stream packets can go over. Besides reading and writing, a session clones it into
+// a reader and a writer half, shuts it down from another thread to interrupt a blocked read
+// and polls it with a read timeout
+pub trait Transport: Read + Write + Send {
+    fn try_clone_box(&self) -> Result<Box<dyn Transport>>;
+
+    fn shutdown(&self, how: Shutdown) -> Result<()>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+
+    // Parallel streams are extra TCP connections to the same peer,
+    // transports other than TCP go without them
+    fn tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone_box(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl Transport for Box<dyn Transport> {
+    fn try_clone_box(&self) -> Result<Box<dyn Transport>> {
+        self.as_ref().try_clone_box()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.as_ref().shutdown(how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.as_ref().set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.as_ref().set_write_timeout(timeout)
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        self.as_ref().tcp()
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn try_clone_box(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+// A connection that is TCP only as far as a relay or a proxy,
+// parallel streams can't reach the peer so it doesn't pass for one
+pub struct Tunneled(pub TcpStream);
+
+impl Read for Tunneled {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Tunneled {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Transport for Tunneled {
+    fn try_clone_box(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(Tunneled(self.0.try_clone()?)))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.0.shutdown(how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.0.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.0.set_write_timeout(timeout)
+    }
+}
+
+// Where the accepting side takes connections from, both when hosting and while waiting
+// for a dropped peer to come back
+pub trait Listener: Sync {
+    // The accepted connection (always blocking) and who it came from
+    fn accept_transport(&self) -> Result<(Box<dyn Transport>, String)>;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()>;
+
+    // Where peers connect to
+    fn describe(&self) -> String;
+
+    // Announced to peers discovering hosts, which only looks for TCP ones
+    fn tcp_port(&self) -> Option<u16> {
+        None
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept_transport(&self) -> Result<(Box<dyn Transport>, String)> {
+        let (stream, address) = self.accept()?;
+        stream.set_nonblocking(false)?;
+        Ok((Box::new(stream), address.ip().to_string()))
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn describe(&self) -> String {
+        match self.local_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => "an unknown address".to_string(),
+        }
+    }
+
+    fn tcp_port(&self) -> Option<u16> {
+        self.local_addr().ok().map(|address| address.port())
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    // unix peers are unnamed, they're on this machine anyway
+    fn accept_transport(&self) -> Result<(Box<dyn Transport>, String)> {
+        let (stream, _) = self.accept()?;
+        stream.set_nonblocking(false)?;
+        Ok((Box::new(stream), "a local process".to_string()))
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        UnixListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn describe(&self) -> String {
+        match self.local_addr().ok().and_then(|address| address.as_pathname().map(|path| path.display().to_string())) {
+            Some(path) => format!("unix socket {path}"),
+            None => "an unnamed unix socket".to_string(),
+        }
+    }
+}
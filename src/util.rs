@@ -1,10 +1,39 @@
+use std::sync::{Mutex, OnceLock};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
 pub fn read_line() -> String {
-    let mut buffer = String::new();
-    return match std::io::stdin().read_line(&mut buffer) {
-        Ok(_) => buffer.trim_end().to_string(),
-        Err(_) => "".into(),
-    };
+    stdin_lines().lock().unwrap().recv().unwrap_or_default()
+}
+
+// None if nothing was entered in time, so that waiting for a command can be given up
+pub fn read_line_timeout(timeout: Duration) -> Option<String> {
+    match stdin_lines().lock().unwrap().recv_timeout(timeout) {
+        Ok(line) => Some(line),
+        Err(RecvTimeoutError::Timeout) => None,
+        Err(RecvTimeoutError::Disconnected) => Some(String::new()),
+    }
+}
+
+// Stdin is read on a thread of its own once a line is first asked for
+fn stdin_lines() -> &'static Mutex<Receiver<String>> {
+    static LINES: OnceLock<Mutex<Receiver<String>>> = OnceLock::new();
+    LINES.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut buffer = String::new();
+            match std::io::stdin().read_line(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {
+                    if sender.send(buffer.trim_end().to_string()).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Mutex::new(receiver)
+    })
 }
 
 const SIZE_UNITS: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
@@ -107,4 +136,155 @@ pub fn get_path_name(path: &str) -> &str {
         return "Unknown";
     };
     return path_name_str;
-}
\ No newline at end of file
+}
+// Identifies files with more than one hard link by (device, inode)
+#[cfg(unix)]
+pub fn hardlink_inode(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    if metadata.nlink() < 2 {
+        return None;
+    }
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn hardlink_inode(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// A relative path that doesn't climb above the directory it's resolved against
+pub fn is_contained_path(path: &str) -> bool {
+    use std::path::Component;
+    let path = std::path::Path::new(path);
+    if path.as_os_str().is_empty() {
+        return false;
+    }
+    let mut depth = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if depth == 0 {
+                    return false;
+                }
+                depth -= 1;
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+#[cfg(unix)]
+pub fn create_symlink(target: &str, link: &std::path::Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+pub fn create_symlink(_target: &str, _link: &std::path::Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "symlinks are not supported on this platform"))
+}
+
+// Parses sizes like 512, 20K, 1.5M or 2GB (powers of 1024)
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_uppercase();
+    let size = size.strip_suffix('B').unwrap_or(&size);
+    let digits_end = size.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits_end);
+    let number = number.parse::<f64>().ok()?;
+    let unit_index = match unit {
+        "" => 0,
+        _ => SIZE_UNITS.iter().position(|u| u.starts_with(unit))?,
+    };
+    Some((number * 1024f64.powi(unit_index as i32)) as u64)
+}
+
+// Shell-like wildcards: * any sequence, ? any character, [abc] or [a-z] a character class
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // where the pattern goes on after the last * and where the text it took so far ends,
+    // a mismatch lets that * take one more character instead of trying every split again
+    let mut backtrack = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            backtrack = Some((p, t));
+            continue;
+        }
+        if let Some(next) = match_one(&pattern, p, text[t]) {
+            p = next;
+            t += 1;
+            continue;
+        }
+        let Some((star_end, taken)) = backtrack else {
+            return false;
+        };
+        backtrack = Some((star_end, taken + 1));
+        p = star_end;
+        t = taken + 1;
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Where the pattern goes on if its element at p matches character
+fn match_one(pattern: &[char], p: usize, character: char) -> Option<usize> {
+    match pattern.get(p)? {
+        '?' => Some(p + 1),
+        '[' => {
+            let Some(close) = pattern[p + 1..].iter().position(|c| *c == ']') else {
+                return (character == '[').then_some(p + 1);
+            };
+            let class = &pattern[p + 1..p + 1 + close];
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    matched |= class[i] <= character && character <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == character;
+                    i += 1;
+                }
+            }
+            matched.then_some(p + close + 2)
+        }
+        literal => (*literal == character).then_some(p + 1),
+    }
+}
+
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+// Splits on whitespace, double quotes group words and are removed
+pub fn split_arguments(line: &str) -> Vec<String> {
+    let mut arguments = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut in_argument = false;
+    for character in line.chars() {
+        match character {
+            '"' => {
+                quoted = !quoted;
+                in_argument = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_argument {
+                    arguments.push(std::mem::take(&mut current));
+                    in_argument = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_argument = true;
+            }
+        }
+    }
+    if in_argument {
+        arguments.push(current);
+    }
+    arguments
+}